@@ -1,16 +1,53 @@
 use crate::utils::barrier;
 use crate::stm32;
 
+#[cfg(feature = "cpu_stm32h503")]
+pub mod chain;
+pub mod circular;
+pub mod transfer;
+
+/// Flags returned by `DMA_Channel::take_flags()`.
+pub const F_HALF: u8 = 1;
+pub const F_FULL: u8 = 2;
+
 #[allow(non_camel_case_types)]
 pub trait DMA_Channel {
-    /// Write to peripheral.  DADDR should be initialized.  The channel should
-    /// be initialised by writes_t0().  Only a size of 0 (bytes) is currently
-    /// supported.
-    fn write(&self, data: usize, len: usize, size: u8);
+    /// Write `len` items to peripheral.  The channel should be initialised by
+    /// writes_to().  The item size is 0 for bytes, 1 for 16-bit or 2 for
+    /// 32-bit, and applies to both memory and peripheral.
+    fn write(&self, data: usize, len: usize, size: u8) {
+        self.write_widths(data, len, size, size);
+    }
+
+    /// Read `len` items from peripheral. The channel should be initialized by
+    /// read_from().  The size is as for `write()`.
+    fn read(&self, data: usize, len: usize, size: u8) {
+        self.read_widths(data, len, size, size);
+    }
 
-    /// Read from peripheral. The channel should be initialized by read_from().
-    /// Only as size of 0 (bytes) is currently supported.
-    fn read(&self, data: usize, len: usize, size: u8);
+    /// As `write()`, with separate memory and peripheral item sizes.  Each
+    /// item is zero extended or truncated to the peripheral size.
+    fn write_widths(&self, data: usize, len: usize, msize: u8, psize: u8);
+
+    /// As `read()`, with separate memory and peripheral item sizes.  Each
+    /// item is zero extended or truncated to the memory size.
+    fn read_widths(&self, data: usize, len: usize, msize: u8, psize: u8);
+
+    /// Read from peripheral continuously into the `len` item buffer at
+    /// `data`, wrapping back to the start when full.  Both the half and full
+    /// transfer interrupts are enabled.  `link` is used by the hardware, and
+    /// must stay in place until the channel is aborted.
+    fn read_circular(&self, data: usize, len: usize, size: u8, link: &Link);
+
+    /// Read and clear the half and full transfer flags, `F_HALF | F_FULL`.
+    fn take_flags(&self) -> u8;
+
+    /// Copy `len` items from memory at `src` to memory at `dst`, without a
+    /// peripheral request.  With `fill`, the single item at `src` is copied
+    /// repeatedly.  This replaces any peripheral set up on the channel.  The
+    /// destination is only complete once `done()`; `remaining()` counts the
+    /// reads.
+    fn copy(&self, dst: usize, src: usize, len: usize, size: u8, fill: bool);
 
     /// Configure to write to a peripheral from memory.
     fn writes_to(&self, dst: *mut u8, request: u8);
@@ -20,6 +57,14 @@ pub trait DMA_Channel {
     /// Stop and cancel an in-process transfer.
     fn abort(&self);
 
+    /// Number of data items not yet transferred.  On the H503, this counts
+    /// the items read from the source, and data may still be in the FIFO, so
+    /// use `done()` for completion.
+    fn remaining(&self) -> usize;
+
+    /// Has the transfer finished, with all the data at its destination?
+    fn done(&self) -> bool;
+
     /// Is the channel busy?
     #[cfg(feature = "cpu_stm32h503")]
     fn busy(&self) -> bool;
@@ -31,20 +76,75 @@ pub type Channel = stm32::gpdma1::c::C;
 #[cfg(any(feature = "cpu_stm32g030", feature = "cpu_stm32u031"))]
 pub type Channel = stm32::dma1::ch::CH;
 
+/// A GPDMA linked-list item, reloading the block size and the destination
+/// address, and linking back to itself, for circular transfers.  The layout
+/// is as loaded by the hardware.
+#[cfg(feature = "cpu_stm32h503")]
+#[repr(C)]
+#[derive_const(Default)]
+pub struct Link {
+    br1: crate::vcell::VCell<u32>,
+    dar: crate::vcell::VCell<u32>,
+    llr: crate::vcell::VCell<u32>,
+}
+
+/// The G030 and U031 DMA has a circular mode, so needs no linked list.
+#[cfg(any(feature = "cpu_stm32g030", feature = "cpu_stm32u031"))]
+#[derive_const(Default)]
+pub struct Link;
+
+/// LLR update bits, each loading a register from the next linked-list item.
+#[cfg(feature = "cpu_stm32h503")]
+const LLR_UB1: u32 = 1 << 29;
+#[cfg(feature = "cpu_stm32h503")]
+const LLR_USA: u32 = 1 << 28;
+#[cfg(feature = "cpu_stm32h503")]
+const LLR_UDA: u32 = 1 << 27;
+#[cfg(feature = "cpu_stm32h503")]
+const LLR_ULL: u32 = 1 << 16;
+
 #[cfg(feature = "cpu_stm32h503")]
 impl DMA_Channel for Channel {
-    fn write(&self, data: usize, len: usize, _size: u8) {
+    fn write_widths(&self, data: usize, len: usize, msize: u8, psize: u8) {
         self.SAR().write(|w| w.SA().bits(data as u32));
-        self.BR1.write(|w| w.BNDT().bits(len as u16));
-        barrier();
-        self.CR.write(|w| w.EN().set_bit().TCIE().set_bit());
+        start(self, len, msize, psize);
     }
 
-    fn read(&self, data: usize, len: usize, _size: u8) {
+    fn read_widths(&self, data: usize, len: usize, msize: u8, psize: u8) {
         self.DAR().write(|w| w.DA().bits(data as u32));
-        self.BR1.write(|w| w.BNDT().bits(len as u16));
+        start(self, len, psize, msize);
+    }
+
+    fn read_circular(&self, data: usize, len: usize, size: u8, link: &Link) {
+        let bndt = (len << size) as u32;
+        let lli = core::ptr::from_ref(link).addr() as u32;
+        link.br1.write(bndt);
+        link.dar.write(data as u32);
+        // Reload the count and destination, and link back to the same item.
+        link.llr.write(LLR_UB1 | LLR_UDA | LLR_ULL | lli & 0xfffc);
+        self.LBAR.write(|w| w.bits(lli & 0xffff0000));
+        self.DAR().write(|w| w.DA().bits(data as u32));
+        self.TR1.modify(|_,w| w.SDW_LOG2().bits(size).DDW_LOG2().bits(size));
+        self.BR1.write(|w| w.bits(bndt));
+        self.LLR.write(|w| w.bits(link.llr.read()));
         barrier();
-        self.CR.write(|w| w.EN().set_bit().TCIE().set_bit());
+        self.CR.write(|w| w.EN().set_bit().TCIE().set_bit().HTIE().set_bit());
+    }
+
+    fn take_flags(&self) -> u8 {
+        let sr = self.SR.read();
+        self.FCR.write(
+            |w| w.HTF().bit(sr.HTF().bit()).TCF().bit(sr.TCF().bit()));
+        (sr.HTF().bit() as u8 * F_HALF) | (sr.TCF().bit() as u8 * F_FULL)
+    }
+
+    fn copy(&self, dst: usize, src: usize, len: usize, size: u8, fill: bool) {
+        self.SAR().write(|w| w.SA().bits(src as u32));
+        self.DAR().write(|w| w.DA().bits(dst as u32));
+        self.TR1.write(|w| w.SINC().bit(!fill).DINC().set_bit());
+        // A software request runs the transfer as fast as it can.
+        self.TR2.write(|w| w.SWREQ().set_bit());
+        start(self, len, size, size);
     }
 
     fn writes_to(&self, dst: *mut u8, request: u8) {
@@ -68,48 +168,106 @@ impl DMA_Channel for Channel {
         }
     }
 
+    /// BNDT counts source bytes, so convert back to items.
+    fn remaining(&self) -> usize {
+        let ssize = self.TR1.read().SDW_LOG2().bits();
+        (self.BR1.read().BNDT().bits() >> ssize) as usize
+    }
+
+    /// The channel disables itself once the last data is written.
+    fn done(&self) -> bool {!self.busy()}
+
     fn busy(&self) -> bool {
         self.CR.read().EN().bit()
     }
 }
 
+/// Set the source and destination widths, and start a transfer of `len`
+/// items.  With differing widths, the GPDMA zero extends or truncates each
+/// item, as the G030 and U031 DMA do.
+#[cfg(feature = "cpu_stm32h503")]
+fn start(ch: &Channel, len: usize, ssize: u8, dsize: u8) {
+    ch.TR1.modify(|_,w| w.SDW_LOG2().bits(ssize).DDW_LOG2().bits(dsize));
+    ch.BR1.write(|w| w.BNDT().bits((len << ssize) as u16));
+    // No link left over from a circular transfer.
+    ch.LLR.write(|w| w.bits(0));
+    barrier();
+    ch.CR.write(|w| w.EN().set_bit().TCIE().set_bit());
+}
+
 #[cfg(any(feature = "cpu_stm32u031", feature = "cpu_stm32g030"))]
 impl DMA_Channel for Channel {
-    fn write(&self, data: usize, len: usize, size: u8) {
-        setup(self, data, len, size, true)}
+    fn write_widths(&self, data: usize, len: usize, msize: u8, psize: u8) {
+        setup(self, data, len, msize, psize, true, false)}
 
-    fn read(&self, data: usize, len: usize, size: u8) {
-        setup(self, data, len, size, false)}
+    fn read_widths(&self, data: usize, len: usize, msize: u8, psize: u8) {
+        setup(self, data, len, msize, psize, false, false)}
+
+    fn read_circular(&self, data: usize, len: usize, size: u8, _: &Link) {
+        setup(self, data, len, size, size, false, true)}
+
+    fn take_flags(&self) -> u8 {
+        // Four flags per channel in ISR and IFCR: GIF, TCIF, HTIF, TEIF.
+        let dma = unsafe {&*stm32::DMA1::ptr()};
+        let shift = index(self) * 4;
+        let isr = dma.ISR.read().bits() >> shift;
+        dma.IFCR.write(|w| w.bits((isr & 6) << shift));
+        (isr & 4 != 0) as u8 * F_HALF | (isr & 2 != 0) as u8 * F_FULL
+    }
+
+    fn copy(&self, dst: usize, src: usize, len: usize, size: u8, fill: bool) {
+        // MEM2MEM reads from MAR and writes to PAR as fast as it can.
+        self.PAR .write(|w| w.bits(dst as u32));
+        self.MAR .write(|w| w.bits(src as u32));
+        self.NDTR.write(|w| w.bits(len as u32));
+        barrier();
+        self.CR.write(
+            |w|w.EN().set_bit().TCIE().set_bit().TEIE().set_bit()
+                .MEM2MEM().set_bit().DIR().set_bit().PINC().set_bit()
+                .MINC().bit(!fill).PSIZE().bits(size).MSIZE().bits(size));
+    }
 
     fn writes_to(&self, dst: *mut u8, request: u8) {
         self.read_from(dst, request);
     }
     fn read_from(&self, src: *const u8, request: u8) {
         self.PAR.write(|w| w.bits(src as u32));
-        // For some reason unsigned_offset_from here leads to crashes.  So
-        // do it by hand.
-        let me = self as *const Self;
-        let dma = unsafe {&*stm32::DMA1::ptr()};
-        let ch0 = dma.CH(0) as *const Self;
-        let index = (me.addr() - ch0.addr()) / size_of::<Self>();
-        // dbgln!("DMA Index = {index}");
         let dmamux = unsafe {&*stm32::DMAMUX::ptr()};
-        dmamux.CCR[index].write(|w| w.bits(request as u32));
+        dmamux.CCR[index(self)].write(|w| w.bits(request as u32));
     }
 
     fn abort(&self) {
         self.CR.write(|w| w);
     }
+
+    fn remaining(&self) -> usize {
+        self.NDTR.read().bits() as usize
+    }
+
+    fn done(&self) -> bool {self.remaining() == 0}
+}
+
+/// The channel number, counting from 0.
+#[cfg(any(feature = "cpu_stm32u031", feature = "cpu_stm32g030"))]
+fn index(ch: &Channel) -> usize {
+    // For some reason unsigned_offset_from here leads to crashes.  So
+    // do it by hand.
+    let me = ch as *const Channel;
+    let dma = unsafe {&*stm32::DMA1::ptr()};
+    let ch0 = dma.CH(0) as *const Channel;
+    (me.addr() - ch0.addr()) / size_of::<Channel>()
 }
 
 #[cfg(any(feature = "cpu_stm32u031", feature = "cpu_stm32g030"))]
-fn setup(ch: &Channel, data: usize, len: usize, size: u8, write: bool) {
+fn setup(ch: &Channel, data: usize, len: usize, msize: u8, psize: u8,
+         write: bool, circular: bool) {
     ch.MAR .write(|w| w.bits(data as u32));
     ch.NDTR.write(|w| w.bits(len as u32));
     barrier();
     ch.CR.write(
         |w|w.EN().set_bit().TCIE().set_bit().TEIE().set_bit().MINC().set_bit()
-            .DIR().bit(write).PSIZE().bits(size).MSIZE().bits(size));
+            .DIR().bit(write).PSIZE().bits(psize).MSIZE().bits(msize)
+            .CIRC().bit(circular).HTIE().bit(circular));
 }
 
 /// Trait Flat is used to check that we pass sane types to things that use DMA.
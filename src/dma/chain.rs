@@ -0,0 +1,191 @@
+//! GPDMA linked-list transfers, chaining several buffers into one DMA job.
+//!
+//! Each buffer gets a linked-list item in RAM, which the GPDMA loads as the
+//! previous block finishes.  For example, a header, payload and CRC may go out
+//! to a peripheral as one transfer, or a cyclic chain can read into each of
+//! several buffers in turn.  The `List` holds both the items and the buffers,
+//! and the `Chain` holds the `List` until the DMA is done with it.
+
+use crate::utils::{WFE, barrier};
+
+use super::{Channel, DMA_Channel, LLR_UB1, LLR_UDA, LLR_ULL, LLR_USA};
+use super::transfer::{ReadBuffer, Word, WriteBuffer};
+
+macro_rules!dbgln {($($tt:tt)*) => {if false {crate::dbgln!($($tt)*)}};}
+
+/// A linked-list item, in the order loaded by the hardware.
+#[repr(C)]
+#[derive(Clone, Copy)]
+#[derive_const(Default)]
+struct Item {
+    br1: u32,
+    sar: u32,
+    dar: u32,
+    llr: u32,
+}
+
+/// Up to `N` buffers, of type `B`, to be transferred in order.
+pub struct List<B, const N: usize> {
+    items: [Item; N],
+    buffers: [Option<B>; N],
+    len: usize,
+}
+
+impl<B, const N: usize> const Default for List<B, N> {
+    fn default() -> Self {
+        List{items: [Item::default(); N], buffers: [const {None}; N], len: 0}
+    }
+}
+
+impl<B, const N: usize> List<B, N> {
+    pub fn len(&self) -> usize {self.len}
+    pub fn is_empty(&self) -> bool {self.len == 0}
+
+    /// Remove all the buffers, handing them back.
+    pub fn clear(&mut self) -> impl Iterator<Item = B> + '_ {
+        let len = self.len;
+        self.len = 0;
+        self.buffers[.. len].iter_mut().filter_map(Option::take)
+    }
+
+    fn push(&mut self, buffer: B, addr: usize, len: usize, size: u8) {
+        assert!(self.len < N, "DMA list full");
+        let bytes = len << size;
+        assert!(bytes <= 0xffff, "DMA list block too long");
+        // Fill in the memory side for now, the peripheral side at start.
+        self.items[self.len] = Item{
+            br1: bytes as u32, sar: addr as u32, dar: addr as u32, llr: 0};
+        self.buffers[self.len] = Some(buffer);
+        self.len += 1;
+    }
+
+    /// Fill in the peripheral address, and link the items.  With `cyclic`,
+    /// the last links back to the first.
+    fn link(&mut self, peripheral: u32, write: bool, cyclic: bool) {
+        assert!(self.len != 0, "DMA list empty");
+        let base = self.items.as_ptr().addr();
+        // LBAR gives the top 16 bits of every item address.
+        let last = base + (self.len - 1) * size_of::<Item>();
+        assert!(base >> 16 == last >> 16, "DMA list crosses 64K boundary");
+        for i in 0 .. self.len {
+            let item = &mut self.items[i];
+            if write {
+                item.dar = peripheral;
+            }
+            else {
+                item.sar = peripheral;
+            }
+            let next = if i + 1 < self.len {Some(i + 1)}
+                else if cyclic {Some(0)}
+                else {None};
+            item.llr = match next {
+                Some(n) => {
+                    let addr = base + n * size_of::<Item>();
+                    // Load the whole of the next item.
+                    LLR_UB1 | LLR_USA | LLR_UDA | LLR_ULL | addr as u32 & 0xfffc
+                },
+                None => 0,
+            };
+        }
+    }
+}
+
+impl<B: ReadBuffer, const N: usize> List<B, N> {
+    /// Add a buffer to be written to the peripheral.
+    pub fn push_write(&mut self, buffer: B) {
+        let (addr, len) = buffer.read_buffer();
+        self.push(buffer, addr, len, B::Word::SIZE);
+    }
+}
+
+impl<B: WriteBuffer, const N: usize> List<B, N> {
+    /// Add a buffer to be read into from the peripheral.
+    pub fn push_read(&mut self, mut buffer: B) {
+        let (addr, len) = buffer.write_buffer();
+        self.push(buffer, addr, len, B::Word::SIZE);
+    }
+}
+
+/// A linked-list transfer in progress.  Dropping it aborts the DMA.
+#[must_use]
+pub struct Chain<B, const N: usize> {
+    channel: &'static Channel,
+    /// `None` once handed back.
+    list: Option<&'static mut List<B, N>>,
+}
+
+impl<B: ReadBuffer, const N: usize> Chain<B, N> {
+    /// Write the buffers in `list` to the peripheral set up by `writes_to()`.
+    /// The transfer interrupt is raised once at the end of the list.
+    pub fn write(channel: &'static Channel, list: &'static mut List<B, N>)
+            -> Self {
+        let peripheral = channel.DAR().read().DA().bits();
+        list.link(peripheral, true, false);
+        start(channel, list, B::Word::SIZE, false);
+        Chain{channel, list: Some(list)}
+    }
+}
+
+impl<B: WriteBuffer, const N: usize> Chain<B, N> {
+    /// Read into the buffers in `list` from the peripheral set up by
+    /// `read_from()`.  With `cyclic`, the chain runs until aborted, with the
+    /// transfer interrupt at the end of each buffer, otherwise the interrupt
+    /// is raised once at the end of the list.
+    pub fn read(channel: &'static Channel, list: &'static mut List<B, N>,
+                cyclic: bool) -> Self {
+        let peripheral = channel.SAR().read().SA().bits();
+        list.link(peripheral, false, cyclic);
+        start(channel, list, B::Word::SIZE, cyclic);
+        Chain{channel, list: Some(list)}
+    }
+}
+
+impl<B, const N: usize> Chain<B, N> {
+    /// The channel disables itself after the last item.
+    pub fn done(&self) -> bool {!self.channel.busy()}
+
+    /// Wait for the transfer to finish, and hand back the list.  Never
+    /// returns for a cyclic chain.  The transfer complete interrupt wakes us
+    /// from WFE.
+    pub fn wait(mut self) -> &'static mut List<B, N> {
+        while !self.done() {
+            WFE();
+        }
+        barrier();
+        self.list.take().unwrap()
+    }
+
+    /// Stop the transfer, and hand back the list.
+    pub fn abort(mut self) -> &'static mut List<B, N> {
+        self.channel.abort();
+        barrier();
+        self.list.take().unwrap()
+    }
+}
+
+impl<B, const N: usize> Drop for Chain<B, N> {
+    fn drop(&mut self) {
+        if self.list.is_some() {
+            self.channel.abort();
+        }
+    }
+}
+
+/// Load the first item into the channel, and go.
+fn start<B, const N: usize>(ch: &Channel, list: &List<B, N>, size: u8,
+                            cyclic: bool) {
+    let first = list.items[0];
+    let base = list.items.as_ptr().addr() as u32;
+    dbgln!("DMA chain of {} at {base:#x}", list.len);
+    ch.abort();
+    ch.LBAR.write(|w| w.bits(base & 0xffff0000));
+    ch.SAR().write(|w| w.SA().bits(first.sar));
+    ch.DAR().write(|w| w.DA().bits(first.dar));
+    ch.TR1.modify(|_,w| w.SDW_LOG2().bits(size).DDW_LOG2().bits(size));
+    // Transfer complete at the end of each block, or of the last item.
+    ch.TR2.modify(|_,w| w.TCEM().bits(if cyclic {0} else {3}));
+    ch.BR1.write(|w| w.bits(first.br1));
+    ch.LLR.write(|w| w.bits(first.llr));
+    barrier();
+    ch.CR.write(|w| w.EN().set_bit().TCIE().set_bit());
+}
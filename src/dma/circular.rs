@@ -0,0 +1,97 @@
+//! Circular DMA reads from a peripheral, e.g., a UART receiver or an ADC.
+//!
+//! The DMA fills the buffer over and over, raising the half and full transfer
+//! interrupts as it goes.  The reader follows behind, and only ever sees data
+//! that the DMA has written.  If the reader falls a whole buffer behind, the
+//! data is lost without notice, so read at least once per half buffer.
+
+use crate::vcell::VCell;
+
+use super::{Channel, DMA_Channel, F_FULL, F_HALF, Link};
+use super::transfer::Word;
+
+macro_rules!dbgln {($($tt:tt)*) => {if false {crate::dbgln!($($tt)*)}};}
+
+pub trait RingMeta {
+    fn channel(&self) -> &'static Channel;
+
+    /// Called from `isr()` once the first half of the buffer is filled.
+    fn half(&mut self) {}
+    /// Called from `isr()` once the second half of the buffer is filled, and
+    /// the DMA has wrapped back to the start.
+    fn full(&mut self) {}
+}
+
+/// A ring buffer of `N` items of the DMA `Word` `T`, e.g., `u8` or `u16`.  `N`
+/// must be even, and the buffer size in bytes must fit in 16 bits.  Both are
+/// checked at build time.
+pub struct Ring<M, T, const N: usize> {
+    buf: VCell<[T; N]>,
+    link: Link,
+    /// Index of the next item to be read.
+    read: VCell<usize>,
+    pub meta: M,
+}
+
+impl<M: const Default, T: Copy + const Default, const N: usize> const Default
+        for Ring<M, T, N> {
+    fn default() -> Self {
+        Ring{buf: VCell::new([T::default(); N]), link: Link::default(),
+             read: VCell::new(0), meta: M::default()}
+    }
+}
+
+impl<M: RingMeta, T: Word, const N: usize> Ring<M, T, N> {
+    /// Start reading from the peripheral register `src`, with the DMA
+    /// request `request`.  Anything already in the buffer is dropped.
+    pub fn start(&self, src: *const u8, request: u8) {
+        const {
+            assert!(N * size_of::<T>() <= 0xffff, "DMA ring too long");
+            assert!(N % 2 == 0, "DMA ring must split into equal halves");
+        }
+        let ch = self.meta.channel();
+        ch.abort();
+        self.read.write(0);
+        ch.read_from(src, request);
+        ch.read_circular(self.buf.as_ptr().addr(), N, T::SIZE, &self.link);
+    }
+
+    pub fn stop(&self) {self.meta.channel().abort()}
+
+    /// Call from the DMA channel interrupt handler.
+    pub fn isr(&mut self) {
+        let flags = self.meta.channel().take_flags();
+        dbgln!("DMA ring ISR {flags} at {}", self.position());
+        if flags & F_HALF != 0 {
+            self.meta.half();
+        }
+        if flags & F_FULL != 0 {
+            self.meta.full();
+        }
+    }
+
+    /// The index of the next item that the DMA will write.
+    pub fn position(&self) -> usize {
+        (N - self.meta.channel().remaining()) % N
+    }
+
+    /// Number of items written by the DMA and not yet read.
+    pub fn available(&self) -> usize {
+        (self.position() + N - self.read.read()) % N
+    }
+
+    /// Copy as many items as are available, up to `out.len()`, into `out`,
+    /// returning the number copied.
+    pub fn read(&self, out: &mut [T]) -> usize {
+        let len = self.available().min(out.len());
+        let mut read = self.read.read();
+        let buf = self.buf.as_ptr() as *const T;
+        for item in &mut out[.. len] {
+            // SAFETY: In bounds, and the DMA has finished with this item.
+            *item = unsafe {core::ptr::read_volatile(buf.add(read))};
+            read = (read + 1) % N;
+        }
+        self.read.write(read);
+        len
+    }
+}
@@ -0,0 +1,171 @@
+//! DMA transfers that own their buffer.
+//!
+//! The `DMA_Channel` calls take raw addresses, and nothing stops the buffer
+//! going away while the DMA is still using it.  A `Transfer` holds on to the
+//! buffer, which must be `'static`, until the DMA is done with it, and aborts
+//! the DMA if dropped early.  Even if the `Transfer` is leaked, the buffer
+//! stays out of reach.
+//!
+//! Completion may be polled with `done()`, or waited for with `wait()`.  The
+//! transfer complete interrupt is enabled, so the channel interrupt handler
+//! can also act on it, via `DMA_Channel::take_flags()`.
+
+use crate::utils::{WFE, barrier};
+
+use super::{DMA_Channel, Flat};
+
+/// A DMA data item, with its size as used by `DMA_Channel`.
+pub trait Word: Flat + Copy {
+    const SIZE: u8;
+}
+
+impl Word for u8  {const SIZE: u8 = 0;}
+impl Word for i16 {const SIZE: u8 = 1;}
+impl Word for u16 {const SIZE: u8 = 1;}
+impl Word for u32 {const SIZE: u8 = 2;}
+
+/// A buffer that the DMA reads from.
+///
+/// # Safety
+/// The address and length returned must describe memory that stays valid and
+/// unmoved for as long as the implementer is alive, even if it is moved.
+pub unsafe trait ReadBuffer {
+    type Word: Word;
+    /// The address, and the length in words.
+    fn read_buffer(&self) -> (usize, usize);
+}
+
+/// A buffer that the DMA writes into.
+///
+/// # Safety
+/// As for `ReadBuffer`, and the memory must not be otherwise accessed while
+/// the implementer is alive.
+pub unsafe trait WriteBuffer {
+    type Word: Word;
+    /// The address, and the length in words.
+    fn write_buffer(&mut self) -> (usize, usize);
+}
+
+unsafe impl<T: Word> ReadBuffer for &'static [T] {
+    type Word = T;
+    fn read_buffer(&self) -> (usize, usize) {(self.as_ptr().addr(), self.len())}
+}
+
+unsafe impl<T: Word, const N: usize> ReadBuffer for &'static [T; N] {
+    type Word = T;
+    fn read_buffer(&self) -> (usize, usize) {(self.as_ptr().addr(), N)}
+}
+
+unsafe impl<T: Word> ReadBuffer for &'static mut [T] {
+    type Word = T;
+    fn read_buffer(&self) -> (usize, usize) {(self.as_ptr().addr(), self.len())}
+}
+
+unsafe impl<T: Word, const N: usize> ReadBuffer for &'static mut [T; N] {
+    type Word = T;
+    fn read_buffer(&self) -> (usize, usize) {(self.as_ptr().addr(), N)}
+}
+
+unsafe impl<T: Word> WriteBuffer for &'static mut [T] {
+    type Word = T;
+    fn write_buffer(&mut self) -> (usize, usize) {
+        (self.as_mut_ptr().addr(), self.len())
+    }
+}
+
+unsafe impl<T: Word, const N: usize> WriteBuffer for &'static mut [T; N] {
+    type Word = T;
+    fn write_buffer(&mut self) -> (usize, usize) {
+        (self.as_mut_ptr().addr(), N)
+    }
+}
+
+/// A transfer in progress on `channel`, using `buffer`.
+#[must_use]
+pub struct Transfer<CH: DMA_Channel + 'static, B> {
+    channel: &'static CH,
+    /// `None` once handed back.
+    buffer: Option<B>,
+}
+
+impl<CH: DMA_Channel, B: ReadBuffer> Transfer<CH, B> {
+    /// Write `buffer` to the peripheral set up by `writes_to()`.
+    pub fn write(channel: &'static CH, buffer: B) -> Self {
+        let (data, len) = buffer.read_buffer();
+        channel.write(data, len, B::Word::SIZE);
+        Transfer{channel, buffer: Some(buffer)}
+    }
+}
+
+impl<CH: DMA_Channel, B: WriteBuffer> Transfer<CH, B> {
+    /// Read into `buffer` from the peripheral set up by `read_from()`.
+    pub fn read(channel: &'static CH, mut buffer: B) -> Self {
+        let (data, len) = buffer.write_buffer();
+        channel.read(data, len, B::Word::SIZE);
+        Transfer{channel, buffer: Some(buffer)}
+    }
+}
+
+impl<CH: DMA_Channel, S: ReadBuffer, D: WriteBuffer> Transfer<CH, (S, D)> {
+    /// Copy `src` into `dst`, memory to memory, as much as fits.  Whole words
+    /// are moved if the addresses and length allow.  The length in bytes must
+    /// fit in 16 bits.  As for the other transfers, `wait()` hands back the
+    /// buffers only once the channel is idle, with `dst` fully written.
+    pub fn copy(channel: &'static CH, src: S, mut dst: D) -> Self {
+        let (saddr, slen) = src.read_buffer();
+        let (daddr, dlen) = dst.write_buffer();
+        let bytes = (slen << S::Word::SIZE).min(dlen << D::Word::SIZE);
+        assert!(bytes <= 0xffff, "DMA copy too long");
+        let size = (saddr | daddr | bytes).trailing_zeros().min(2) as u8;
+        channel.copy(daddr, saddr, bytes >> size, size, false);
+        Transfer{channel, buffer: Some((src, dst))}
+    }
+
+    /// Fill `dst` with copies of the first word of `pattern`, e.g., `&[0u32]`
+    /// to clear a buffer a word at a time.  `dst` must be aligned to, and a
+    /// multiple of, the pattern word size.
+    pub fn fill(channel: &'static CH, pattern: S, mut dst: D) -> Self {
+        let (saddr, slen) = pattern.read_buffer();
+        let (daddr, dlen) = dst.write_buffer();
+        let size = S::Word::SIZE;
+        let bytes = dlen << D::Word::SIZE;
+        assert!(slen != 0, "DMA fill pattern empty");
+        assert!((daddr | bytes) & (1 << size) - 1 == 0,
+                "DMA fill misaligned");
+        assert!(bytes <= 0xffff, "DMA fill too long");
+        channel.copy(daddr, saddr, bytes >> size, size, true);
+        Transfer{channel, buffer: Some((pattern, dst))}
+    }
+}
+
+impl<CH: DMA_Channel, B> Transfer<CH, B> {
+    pub fn done(&self) -> bool {self.channel.done()}
+
+    /// Number of words not yet transferred, see `DMA_Channel::remaining()`.
+    pub fn remaining(&self) -> usize {self.channel.remaining()}
+
+    /// Wait for the transfer to finish, and hand back the buffer.  The
+    /// transfer complete interrupt must be enabled, to wake us from WFE.
+    pub fn wait(mut self) -> B {
+        while !self.done() {
+            WFE();
+        }
+        barrier();
+        self.buffer.take().unwrap()
+    }
+
+    /// Stop the transfer, and hand back the buffer.
+    pub fn abort(mut self) -> B {
+        self.channel.abort();
+        barrier();
+        self.buffer.take().unwrap()
+    }
+}
+
+impl<CH: DMA_Channel, B> Drop for Transfer<CH, B> {
+    fn drop(&mut self) {
+        if self.buffer.is_some() {
+            self.channel.abort();
+        }
+    }
+}
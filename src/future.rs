@@ -0,0 +1,94 @@
+//! Support for async completion of interrupt driven hardware operations.
+//!
+//! Interrupt handlers wake futures via a `WakerCell`.  Any executor can be
+//! used; `block_on()` is a minimal one that sleeps in WFE between polls.
+
+use core::pin::{Pin, pin};
+use core::task::{Context, Poll, Waker};
+
+use crate::interrupt::free;
+use crate::utils::{WFE, barrier};
+use crate::vcell::{UCell, VCell};
+
+/// Storage for the waker of a single task waiting on an interrupt.
+#[derive_const(Default)]
+pub struct WakerCell(UCell<Option<Waker>>);
+
+impl WakerCell {
+    pub const fn new() -> Self {Self(UCell::new(None))}
+
+    /// Register the waker to be woken by the next `wake()`.
+    pub fn register(&self, waker: &Waker) {
+        free(|| {
+            // SAFETY: Interrupts are disabled.
+            let slot = unsafe {self.0.as_mut()};
+            if !slot.as_ref().is_some_and(|w| w.will_wake(waker)) {
+                *slot = Some(waker.clone());
+            }
+        });
+    }
+
+    /// Wake the registered waker, if any.  Usually called from an ISR.
+    pub fn wake(&self) {
+        // SAFETY: Interrupts are disabled.
+        if let Some(waker) = free(|| unsafe {self.0.as_mut()}.take()) {
+            waker.wake();
+        }
+    }
+}
+
+/// A completion flag with a waker.  The thread side calls `arm()` before
+/// starting an operation, and the interrupt handler (e.g., a DMA channel ISR,
+/// or a USB endpoint handler) calls `signal()` when it is done.
+#[derive_const(Default)]
+pub struct Signal {
+    pending: VCell<bool>,
+    waker: WakerCell,
+}
+
+impl Signal {
+    pub const fn new() -> Self {
+        Self{pending: VCell::new(false), waker: WakerCell::new()}
+    }
+    pub fn arm(&self) {
+        self.pending.write(true);
+        barrier();
+    }
+    pub fn signal(&self) {
+        barrier();
+        self.pending.write(false);
+        self.waker.wake();
+    }
+    pub fn done(&self) -> bool {!self.pending.read()}
+
+    pub fn poll(&self, cx: &mut Context<'_>) -> Poll<()> {
+        self.waker.register(cx.waker());
+        if self.done() {Poll::Ready(())} else {Poll::Pending}
+    }
+
+    /// Future for the next `signal()`.
+    pub fn wait(&self) -> SignalWait<'_> {SignalWait(self)}
+}
+
+#[must_use]
+pub struct SignalWait<'a>(&'a Signal);
+
+impl Future for SignalWait<'_> {
+    type Output = ();
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        self.0.poll(cx)
+    }
+}
+
+/// Run a future to completion, sleeping in WFE while it is pending.  Any
+/// interrupt wakes us to poll again, so the waker itself does nothing.
+pub fn block_on<F: Future>(f: F) -> F::Output {
+    let mut f = pin!(f);
+    let mut cx = Context::from_waker(Waker::noop());
+    loop {
+        if let Poll::Ready(result) = f.as_mut().poll(&mut cx) {
+            return result;
+        }
+        WFE();
+    }
+}
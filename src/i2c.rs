@@ -1,25 +1,207 @@
+pub mod bus;
+mod drive;
+pub mod eeprom;
+#[cfg(feature = "embedded_hal")]
+mod hal;
+mod recovery;
+mod retry;
+#[cfg(not(feature = "cpu_stm32g030"))]
+pub mod smbus;
+mod state;
+mod stats;
+pub mod target;
+mod timing;
+mod wakeup;
+
+pub use drive::NoFastModePlus;
+pub use recovery::Pin;
+pub use stats::{Stats, TraceEntry};
+pub use timing::{Speed, Timing};
+
 
 use crate::vcell::VCell;
 use crate::utils::{WFE, barrier};
 use crate::dma::{Channel, DMA_Channel};
+use crate::future::WakerCell;
+
+use retry::{Replay, Retry};
+use state::{Action, Machine};
+use stats::{Counters, Trace};
+
+use core::task::{Context, Poll};
+
+/// Errors reported by I2C transactions.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum I2cError {
+    /// The target did not acknowledge its address, usually meaning that the
+    /// device is absent.
+    AddressNack,
+    /// The target did not acknowledge a written byte.  The index counts the
+    /// bytes sent after the address, including any register byte.  On the
+    /// H503 the GPDMA FIFO may prefetch data, so treat it as approximate.
+    DataNack(usize),
+    /// Arbitration lost to another controller.
+    ArbitrationLost,
+    /// Misplaced START or STOP on the bus.
+    BusError,
+    /// Overrun or underrun.
+    Overrun,
+    /// DMA transfer error.
+    Dma,
+    /// SMBus Packet Error Code mismatch on a read.
+    Pec,
+    /// Hardware SCL-low timeout, or the caller supplied deadline passed.
+    Timeout,
+    /// The hardware reported an event that does not fit the transaction,
+    /// e.g., a STOP before all the data was transferred.
+    Unexpected,
+}
+
+pub type Result<T = ()> = core::result::Result<T, I2cError>;
+
+/// A target address.  `Seven` is in the 8-bit form used throughout this
+/// crate, i.e., the 7-bit address shifted left by one, with the R/W bit
+/// ignored.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Address {
+    Seven(u8),
+    Ten(u16),
+}
+
+impl From<u8> for Address {
+    fn from(addr: u8) -> Self {Address::Seven(addr)}
+}
+
+/// A register address, sent big-endian ahead of the data by `read_reg_start()`
+/// and `write_reg_start()`.
+pub trait RegAddr: Copy {
+    /// Number of address bytes.
+    const LEN: u8;
+    fn to_u32(self) -> u32;
+}
+
+impl RegAddr for u8 {
+    const LEN: u8 = 1;
+    fn to_u32(self) -> u32 {self as u32}
+}
+
+impl RegAddr for u16 {
+    const LEN: u8 = 2;
+    fn to_u32(self) -> u32 {self as u32}
+}
+
+impl RegAddr for u32 {
+    const LEN: u8 = 4;
+    fn to_u32(self) -> u32 {self}
+}
+
+type CR2W = crate::stm32::i2c1::cr2::W;
+
+impl Address {
+    /// Program the address into CR2.  With `head10r`, a 10-bit read sends
+    /// only the header byte after the START, which is correct for a repeated
+    /// START to a device we have just written to.
+    fn cr2(self, w: &mut CR2W, head10r: bool) -> &mut CR2W {
+        match self {
+            Address::Seven(addr) =>
+                w.SADD().bits(addr as u16).ADD10().clear_bit(),
+            Address::Ten(addr) =>
+                w.SADD().bits(addr & 0x3ff).ADD10().set_bit()
+                    .HEAD10R().bit(head10r),
+        }
+    }
+}
 
-pub type Result = core::result::Result<(), ()>;
+/// Set CR2.PECBYTE.  The G030 has no SMBus support, so there it does nothing.
+#[cfg(not(feature = "cpu_stm32g030"))]
+fn pecbyte(w: &mut CR2W, pec: bool) -> &mut CR2W {w.PECBYTE().bit(pec)}
+#[cfg(feature = "cpu_stm32g030")]
+fn pecbyte(w: &mut CR2W, _: bool) -> &mut CR2W {w}
 
 #[derive_const(Default)]
 pub struct I2cContext<M> {
     pub outstanding: VCell<u8>,
-    error: VCell<u8>,
-    pending_len: VCell<usize>,
+    /// The flags most recently passed to `arm()`.
+    armed: VCell<u8>,
+    error: VCell<Option<I2cError>>,
+    /// The result of the transaction has been returned, see `result()`.
+    reported: VCell<bool>,
+    /// Transaction state, driven by the ISR.
+    machine: Machine,
+    /// Operations ending with a STOP also handle an SMBus PEC byte.
+    pec: VCell<bool>,
+    /// Register address bytes still to send, from the ISR, ahead of the data.
+    prefix: VCell<u32>,
+    prefix_len: VCell<u8>,
+    /// Buffer positions for transfers without DMA, and for the DMA following
+    /// a register address.
+    tx_ptr: VCell<usize>,
+    tx_end: VCell<usize>,
+    rx_ptr: VCell<usize>,
+    rx_end: VCell<usize>,
+    /// Arbitration loss retry, see `Meta::ARBITRATION_RETRIES`.
+    retry: Retry,
+    counters: Counters,
+    trace: Trace,
+    /// Woken when the transaction completes.
+    waker: WakerCell,
     pub meta: M,
 }
 
 pub trait Meta {
     fn i2c(&self) -> &'static crate::stm32::i2c1::RegisterBlock;
-    fn rx_channel(&self) -> &'static Channel;
-    fn tx_channel(&self) -> &'static Channel;
+    /// The DMA channels, and their DMA request numbers.  Needed if `DMA` is
+    /// true, and always in target mode.  The default channels panic, so that
+    /// a controller with `DMA` false need not give them.
+    fn rx_channel(&self) -> &'static Channel {panic!("I2C without DMA")}
+    fn tx_channel(&self) -> &'static Channel {panic!("I2C without DMA")}
+
+    fn rx_muxin(&self) -> u8 {0}
+    fn tx_muxin(&self) -> u8 {0}
+
+    /// Hardware SCL-low timeout, as a TIMEOUTR.TIMEOUTA value, in units of
+    /// 2048 I2C kernel clocks.  Ignored on the G030, which does not have it.
+    fn timeout(&self) -> Option<u16> {None}
+
+    /// SCL and SDA pins, for bus recovery by `error_cleanup()`, and for the
+    /// Fm+ drive.  If either is `None`, no recovery is attempted.
+    fn scl_pin(&self) -> Option<Pin> {None}
+    fn sda_pin(&self) -> Option<Pin> {None}
 
-    fn rx_muxin(&self) -> u8;
-    fn tx_muxin(&self) -> u8;
+    /// Enable the SMBALERT# input.  Ignored on the G030.
+    fn alert_enable(&self) -> bool {false}
+    /// Called from the ISR when SMBALERT# is asserted.  Typically, arrange for
+    /// `SmBus::alert_response()` to be called to find out who is alerting.
+    fn alert(&mut self) {}
+
+    /// Transfer data by DMA.  If false, the data is moved by the interrupt
+    /// handler, on TXIS and RXNE, instead.  Target mode always uses DMA.
+    const DMA: bool = true;
+
+    /// On a multi-master bus, how many times `wait()` and `wait_until()`
+    /// restart a transaction that loses arbitration, before reporting
+    /// `ArbitrationLost`.  Only these blocking waits retry: awaiting a
+    /// transaction, including through the embedded-hal async traits, reports
+    /// the error at once, and `I2cBus` refuses a non-zero value at build time.
+    const ARBITRATION_RETRIES: u8 = 0;
+
+    /// CPU frequency, in Hz, for timing the bus recovery clock.  The default
+    /// is pessimistic and just gives a slower clock.
+    const CPU_FREQ: u32 = 250_000_000;
+}
+
+/// How an operation started by `operation_start()` finishes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[derive_const(Default)]
+pub enum OpEnd {
+    /// Generate a STOP.
+    #[default]
+    Stop,
+    /// Hold the bus, for a following operation with a repeated START.
+    Restart,
+    /// Hold the bus, for a following operation continuing in the same
+    /// direction without a repeated START.
+    Continue,
 }
 
 pub const F_I2C: u8 = 1;
@@ -32,37 +214,40 @@ impl<M: Meta> I2cContext<M> {
     pub fn isr(&mut self) {
         let i2c = self.meta.i2c();
 
-        let status = i2c.ISR.read();
-        dbgln!("I2C ISR {:#x}", status.bits());
-        let todo = *self.pending_len.as_mut();
-        *self.pending_len.as_mut() = 0;
+        let mut status = i2c.ISR.read().bits();
+        if !i2c.CR1.read().TCIE().bit() {
+            // TC or TCR is left set while the bus is held, and must not be
+            // seen by an interrupt for some other event either.
+            status &= !(state::TC | state::TCR);
+        }
+        dbgln!("I2C ISR {status:#x} {:?}", self.machine.state());
+        self.trace.isr(status);
+        self.transfer_data(status);
 
-        if todo != 0 && status.TC().bit() {
-            // Assume write -> read transition.
-            dbgln!("I2C now read {todo} bytes [{:#x}]", status.bits());
-            let cr2 = i2c.CR2.read();
-            i2c.CR2.write(
-                |w|w.NBYTES().bits(todo as u8).START().set_bit()
-                    .AUTOEND().set_bit().RD_WRN().set_bit()
-                    .SADD().bits(cr2.SADD().bits()));
-        }
-        else if status.STOPF().bit() {
-            // FIXME - if we see a stop when waiting for the above, we'll hang.
-            dbgln!("I2C STOPF");
-            i2c.ICR.write(|w| w.STOPCF().set_bit());
-            *self.outstanding.as_mut() &= !F_I2C;
-        }
-        else if status.ARLO().bit() || status.BERR().bit()
-            || status.NACKF().bit() {
-            dbgln!("I2C Error");
-            i2c.ICR.write(
-                |w| w.ARLOCF().set_bit().BERRCF().set_bit().NACKCF().set_bit());
-            *self.outstanding.as_mut() = 0;
-            *self.error.as_mut() = 1;
+        i2c.ICR.write(|w| w.bits(status & state::CLEAR));
+        if status & state::ALERT != 0 {
+            dbgln!("I2C SMBALERT");
+            self.meta.alert();
         }
-        else {
-            panic!("Unexpected I2C ISR {:#x} {:#x}", status.bits(),
-                   i2c.CR2.read().bits());
+        match self.machine.event(status, || self.tx_remaining()) {
+            Action::None => (),
+            Action::Reload{nbytes, reload} => i2c.CR2.modify(
+                |_,w| w.NBYTES().bits(nbytes).RELOAD().bit(reload)),
+            Action::Restart{nbytes, reload} => {
+                let cr2 = i2c.CR2.read();
+                i2c.CR2.write(
+                    |w|w.NBYTES().bits(nbytes).RELOAD().bit(reload)
+                        .START().set_bit().AUTOEND().set_bit()
+                        .RD_WRN().set_bit().SADD().bits(cr2.SADD().bits())
+                        .ADD10().bit(cr2.ADD10().bit()).HEAD10R().set_bit());
+            },
+            Action::Hold => {
+                // Mask TC/TCR until the next operation clears them.
+                i2c.CR1.modify(|_,w| w.TCIE().clear_bit());
+                *self.outstanding.as_mut() &= !F_I2C;
+            },
+            Action::Done => *self.outstanding.as_mut() &= !F_I2C,
+            Action::Error(error) => self.set_error(error),
         }
         // Stop the ISR from prematurely retriggering.  Otherwise we may return
         // from the ISR before the update has propagated through the I2C
@@ -70,105 +255,544 @@ impl<M: Meta> I2cContext<M> {
         i2c.ISR.read();
 
         dbgln!("I2C ISR done, {}", self.outstanding.read());
+        if self.done() {
+            self.waker.wake();
+        }
     }
 
-    pub fn read_reg_start(&self, addr: u8, reg: u8, data: usize, len: usize) {
-        // Should only be called while I2C idle...
+    /// Call from the DMA channel interrupt handlers, with `flag` being
+    /// `F_DMA_RX` or `F_DMA_TX`, once the channel has finished.
+    pub fn dma_isr(&mut self, flag: u8, error: bool) {
+        if error {
+            dbgln!("I2C DMA error");
+            self.set_error(I2cError::Dma);
+        }
+        else {
+            *self.outstanding.as_mut() &= !flag;
+        }
+        if self.done() {
+            self.waker.wake();
+        }
+    }
+
+    /// Record an error, and abandon the transaction.  The first error wins.
+    fn set_error(&mut self, error: I2cError) {
+        if self.error.as_mut().is_none() {
+            *self.error.as_mut() = Some(error);
+            self.counters.error(error);
+        }
+        *self.outstanding.as_mut() = 0;
+    }
+
+    /// Move data through RXDR and TXDR, for register addresses and for
+    /// transfers without DMA.
+    fn transfer_data(&self, status: u32) {
         let i2c = self.meta.i2c();
-        self.arm(F_I2C | F_DMA_RX);
-        self.pending_len.write(len);
+        let cr1 = i2c.CR1.read();
+        if status & state::RXNE != 0 && cr1.RXIE().bit() {
+            let byte = i2c.RXDR.read().RXDATA().bits();
+            let ptr = self.rx_ptr.read();
+            // Bytes beyond the buffer are discarded.
+            if ptr < self.rx_end.read() {
+                // SAFETY: The caller of the start function guarantees the
+                // buffer.
+                unsafe {*(ptr as *mut u8) = byte};
+                self.rx_ptr.write(ptr + 1);
+            }
+            if ptr + 1 >= self.rx_end.read() {
+                i2c.CR1.modify(|_,w| w.RXIE().clear_bit());
+            }
+        }
+        if status & state::TXIS != 0 && cr1.TXIE().bit() {
+            let prefix_len = self.prefix_len.read();
+            let ptr = self.tx_ptr.read();
+            let end = self.tx_end.read();
+            // TXIS is only raised while NBYTES has bytes to go, so we should
+            // never run out, but pad rather than stall if we do.
+            let byte = if prefix_len != 0 {
+                self.prefix_len.write(prefix_len - 1);
+                (self.prefix.read() >> 8 * (prefix_len - 1)) as u8
+            }
+            else if ptr < end {
+                self.tx_ptr.write(ptr + 1);
+                // SAFETY: As above.
+                unsafe {*(ptr as *const u8)}
+            }
+            else {
+                0xff
+            };
+            i2c.TXDR.write(|w| w.TXDATA().bits(byte));
+            if M::DMA && prefix_len <= 1 {
+                // The register address is out, hand over to the DMA.
+                i2c.CR1.modify(|_,w| w.TXIE().clear_bit());
+                if end > ptr {
+                    self.meta.tx_channel().write(ptr, end - ptr, 0);
+                }
+            }
+            else if prefix_len <= 1 && self.tx_ptr.read() >= end {
+                i2c.CR1.modify(|_,w| w.TXIE().clear_bit());
+            }
+        }
+    }
 
-        // Synchronous I2C start for the reg ptr write.
-        // No DMA write is active so the dma req. hopefully just gets ignored.
-        i2c.CR2.write(
-            |w| w.START().set_bit().SADD().bits(addr as u16).NBYTES().bits(1));
-        i2c.TXDR.write(|w| w.bits(reg as u32));
+    /// Start sending `len` bytes from `data`, by DMA or by interrupt.
+    fn tx_start(&self, data: usize, len: usize) {
+        if M::DMA {
+            self.meta.tx_channel().write(data, len, 0);
+        }
+        else {
+            self.prefix_len.write(0);
+            self.tx_ptr.write(data);
+            self.tx_end.write(data + len);
+            barrier();
+            self.meta.i2c().CR1.modify(|_,w| w.TXIE().set_bit());
+        }
+    }
+
+    /// Start sending the register address `reg` from the ISR, followed by
+    /// `len` bytes from `data`, by DMA or by interrupt.
+    fn reg_start(&self, reg: u32, reg_len: u8, data: usize, len: usize) {
+        self.prefix.write(reg);
+        self.prefix_len.write(reg_len);
+        self.tx_ptr.write(data);
+        self.tx_end.write(data + len);
+        barrier();
+        self.meta.i2c().CR1.modify(|_,w| w.TXIE().set_bit());
+    }
+
+    /// Start receiving `len` bytes into `data`, by DMA or by interrupt.
+    fn rx_start(&self, data: usize, len: usize) {
+        if M::DMA {
+            self.meta.rx_channel().read(data, len, 0);
+        }
+        else {
+            self.rx_ptr.write(data);
+            self.rx_end.write(data + len);
+            barrier();
+            self.meta.i2c().CR1.modify(|_,w| w.RXIE().set_bit());
+        }
+    }
 
-        self.meta.rx_channel().read(data, len, 0);
+    /// Bytes of the write phase not yet taken by the DMA or the interrupt
+    /// handler.
+    fn tx_remaining(&self) -> usize {
+        let prefix_len = self.prefix_len.read() as usize;
+        if !M::DMA || prefix_len != 0 {
+            prefix_len + self.tx_end.read().saturating_sub(self.tx_ptr.read())
+        }
+        else if self.armed.read() & F_DMA_TX != 0 {
+            self.meta.tx_channel().remaining()
+        }
+        else {
+            0
+        }
+    }
+
+    /// Write the register address `reg`, and then read `len` bytes after a
+    /// repeated START.
+    pub fn read_reg_start<R: RegAddr>(&self, addr: impl Into<Address>, reg: R,
+                                      data: usize, len: usize) {
+        self.read_reg_op(addr.into(), reg.to_u32(), R::LEN, data, len);
+    }
+    fn read_reg_op(&self, addr: Address, reg: u32, reg_len: u8,
+                   data: usize, len: usize) {
+        // Should only be called while I2C idle...
+        let i2c = self.meta.i2c();
+        self.record(Replay::ReadReg{addr, reg, reg_len, data, len});
+        self.machine.start(false, reg_len as usize, OpEnd::Stop, len);
+        self.reg_start(reg, reg_len, 0, 0);
+        // A zero length DMA would never finish.
+        if len != 0 {
+            self.rx_start(data, len);
+        }
+        self.trace.start(addr, reg_len as usize, len);
+        self.arm(if len != 0 {F_I2C | F_DMA_RX} else {F_I2C});
+        let autoend = self.machine.autoend();
+        i2c.CR2.write(
+            |w| addr.cr2(w, false).START().set_bit().AUTOEND().bit(autoend)
+                .NBYTES().bits(reg_len));
+        self.unmask_tc();
     }
     #[inline(never)]
-    pub fn read_start(&self, addr: u8, data: usize, len: usize) {
+    pub fn read_start(&self, addr: impl Into<Address>,
+                      data: usize, len: usize) {
         let i2c = self.meta.i2c();
+        let addr = addr.into();
 
-        self.meta.rx_channel().read(data, len, 0);
+        self.record(Replay::Read{addr, data, len});
+        self.rx_start(data, len);
+        let (nbytes, reload) = self.machine.start(true, len, OpEnd::Stop, 0);
+        self.trace.start(addr, 0, len);
         self.arm(F_I2C | F_DMA_RX);
         i2c.CR2.write(
-            |w|w.START().set_bit().AUTOEND().bit(true).SADD().bits(addr as u16)
-                .RD_WRN().set_bit().NBYTES().bits(len as u8));
+            |w|addr.cr2(w, false).START().set_bit().AUTOEND().bit(true)
+                .RD_WRN().set_bit().NBYTES().bits(nbytes).RELOAD().bit(reload));
+        self.unmask_tc();
     }
+    /// Write the register address `reg`, followed by `len` bytes of data.
     #[inline(never)]
-    pub fn write_reg_start(&self, addr: u8, reg: u8, data: usize, len: usize) {
+    pub fn write_reg_start<R: RegAddr>(&self, addr: impl Into<Address>,
+                                       reg: R, data: usize, len: usize) {
+        self.write_reg_op(addr.into(), reg.to_u32(), R::LEN, data, len);
+    }
+    fn write_reg_op(&self, addr: Address, reg: u32, reg_len: u8,
+                    data: usize, len: usize) {
         let i2c = self.meta.i2c();
 
-        self.arm(F_I2C | F_DMA_TX);
+        self.record(Replay::WriteReg{addr, reg, reg_len, data, len});
+        let (nbytes, reload) = self.machine.start(
+            false, reg_len as usize + len, OpEnd::Stop, 0);
+        self.reg_start(reg, reg_len, data, len);
+        self.trace.start(addr, reg_len as usize + len, 0);
+        // The ISR only starts the DMA if there is data.
+        self.arm(if len != 0 {F_I2C | F_DMA_TX} else {F_I2C});
         i2c.CR2.write(
-            |w| w.START().set_bit().AUTOEND().set_bit()
-                . SADD().bits(addr as u16).NBYTES().bits(len as u8 + 1));
-        i2c.TXDR.write(|w| w.TXDATA().bits(reg));
-        self.meta.tx_channel().write(data, len, 0);
+            |w| addr.cr2(w, false).START().set_bit().AUTOEND().set_bit()
+                . NBYTES().bits(nbytes)
+                . RELOAD().bit(reload));
+        self.unmask_tc();
     }
     #[inline(never)]
-    pub fn write_start(&self, addr: u8, data: usize, len: usize, last: bool) {
+    pub fn write_start(&self, addr: impl Into<Address>, data: usize,
+                       len: usize, last: bool) {
         let i2c = self.meta.i2c();
+        let addr = addr.into();
 
-        self.arm(F_I2C | F_DMA_TX);
+        let end = if last {OpEnd::Stop} else {OpEnd::Restart};
+        self.record(if last {Replay::Write{addr, data, len}}
+                    else {Replay::None});
+        let (nbytes, reload) = self.machine.start(false, len, end, 0);
+        self.trace.start(addr, len, 0);
+        self.arm(if len != 0 {F_I2C | F_DMA_TX} else {F_I2C});
         i2c.CR2.write(
-            |w| w.START().set_bit().AUTOEND().bit(last)
-                . SADD().bits(addr as u16).NBYTES().bits(len as u8));
-        self.meta.tx_channel().write(data, len, 0);
+            |w| addr.cr2(w, false).START().set_bit().AUTOEND().bit(last)
+                . NBYTES().bits(nbytes)
+                . RELOAD().bit(reload));
+        self.unmask_tc();
+        if len != 0 {
+            self.tx_start(data, len);
+        }
     }
 
     #[inline(never)]
-    pub fn write_read_start(&self, addr: u8, wdata: usize, wlen: usize,
-                        rdata: usize, rlen: usize) {
+    pub fn write_read_start(&self, addr: impl Into<Address>,
+                            wdata: usize, wlen: usize,
+                            rdata: usize, rlen: usize) {
         let i2c = self.meta.i2c();
-        self.meta.tx_channel().write(wdata, wlen, 0);
-        self.meta.rx_channel().read (rdata, rlen, 0);
-        self.pending_len.write(rlen);
-        self.arm(F_I2C | F_DMA_TX | F_DMA_RX);
+        let addr = addr.into();
+        self.record(Replay::WriteRead{addr, wdata, wlen, rdata, rlen});
+        // A zero length DMA would never finish.
+        let mut flags = F_I2C;
+        if wlen != 0 {
+            self.tx_start(wdata, wlen);
+            flags |= F_DMA_TX;
+        }
+        if rlen != 0 {
+            self.rx_start(rdata, rlen);
+            flags |= F_DMA_RX;
+        }
+        let (nbytes, reload) = self.machine.start(
+            false, wlen, OpEnd::Stop, rlen);
+        self.trace.start(addr, wlen, rlen);
+        self.arm(flags);
+        let autoend = self.machine.autoend();
         i2c.CR2.write(
-            |w|w.START().set_bit().SADD().bits(addr as u16)
-                .NBYTES().bits(wlen as u8));
+            |w|addr.cr2(w, false).START().set_bit().AUTOEND().bit(autoend)
+                .NBYTES().bits(nbytes).RELOAD().bit(reload));
+        self.unmask_tc();
+    }
+    /// Start one operation of a multi-operation transaction.  With `start`,
+    /// a (repeated) START and the address are sent.  Otherwise the data
+    /// carries straight on from the previous operation, which must have been
+    /// in the same direction and ended with `OpEnd::Continue`.
+    ///
+    /// After `set_pec(true)`, an operation ending with a STOP also sends or
+    /// checks a PEC byte.  For a read, `len` includes the PEC byte, which is
+    /// received into the buffer after the data.
+    pub fn operation_start(&self, addr: Address, read: bool, data: usize,
+                           len: usize, start: bool, end: OpEnd) {
+        let i2c = self.meta.i2c();
+        // If TC is set, then the bus is held after a previous operation, so a
+        // 10-bit read need only resend the header.
+        let head10r = i2c.ISR.read().TC().bit();
+        self.record(if start && end == OpEnd::Stop {
+            Replay::Operation{addr, read, data, len}} else {Replay::None});
+        let mut flags = F_I2C;
+        if len != 0 && read {
+            self.rx_start(data, len);
+            flags |= F_DMA_RX;
+        }
+        if len != 0 && !read {
+            self.tx_start(data, len);
+            flags |= F_DMA_TX;
+        }
+        let autoend = end == OpEnd::Stop;
+        // The PEC byte is generated by the hardware on writes, but is read
+        // like data.
+        let pec = autoend && self.pec.read();
+        let (nbytes, reload) = self.machine.start(
+            read, len + (pec && !read) as usize, end, 0);
+        if read {
+            self.trace.start(addr, 0, len);
+        }
+        else {
+            self.trace.start(addr, len, 0);
+        }
+        self.arm(flags);
+        if start {
+            i2c.CR2.write(
+                |w|pecbyte(addr.cr2(w, head10r), pec).START().set_bit()
+                    .RD_WRN().bit(read).NBYTES().bits(nbytes)
+                    .RELOAD().bit(reload).AUTOEND().bit(autoend));
+        }
+        else {
+            i2c.CR2.modify(
+                |_,w|pecbyte(w, pec).NBYTES().bits(nbytes).RELOAD().bit(reload)
+                    .AUTOEND().bit(autoend));
+        }
+        self.unmask_tc();
+    }
+
+    /// Check whether a device answers at the 7-bit address `addr`, with
+    /// either a zero length write or a one byte read.  Blocks until done.
+    pub fn probe(&self, addr: u8, read: bool) -> Result<bool> {
+        let mut byte = 0u8;
+        let data = core::ptr::from_mut(&mut byte).addr();
+        self.operation_start(Address::Seven(addr << 1), read, data,
+                             read as usize, true, OpEnd::Stop);
+        match self.wait() {
+            Ok(()) => Ok(true),
+            Err(I2cError::AddressNack) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Check for an ACK at the 7-bit address `addr`, with a zero length
+    /// write.  Unlike `probe()`, a NACK is an expected outcome, e.g., from an
+    /// EEPROM busy with a write cycle, so it is neither counted as an error
+    /// nor followed by an I2C reset.  Blocks until done.
+    pub fn ack_poll(&self, addr: u8) -> Result<bool> {
+        self.counters.quiet(true);
+        self.operation_start(Address::Seven(addr << 1), false, 0, 0,
+                             true, OpEnd::Stop);
+        while !self.done() {
+            WFE();
+        }
+        self.counters.quiet(false);
+        barrier();
+        if self.error.read() != Some(I2cError::AddressNack) {
+            return self.result().map(|()| true);
+        }
+        // The hardware sends the STOP after the NACK by itself.  Let it go
+        // out, so that it does not end the next operation.
+        let i2c = self.meta.i2c();
+        for _ in 0 .. 100 {
+            if !i2c.ISR.read().BUSY().bit() {
+                break;
+            }
+            self.half_clock();
+        }
+        self.error.write(None);
+        self.machine.reset();
+        self.finish(Err(I2cError::AddressNack));
+        Ok(false)
+    }
+
+    /// Probe all the non-reserved 7-bit addresses, returning a bitmap with
+    /// bit `n` set if a device answered at address `n`.  Errors other than
+    /// NACKs abort the scan.
+    pub fn scan(&self, read: bool) -> Result<u128> {
+        let mut found = 0;
+        for addr in 0x08 .. 0x78 {
+            if self.probe(addr, read)? {
+                found |= 1 << addr;
+            }
+        }
+        Ok(found)
+    }
+
+    /// Enable or disable the SMBus PEC handling of `operation_start()`.
+    pub fn set_pec(&self, pec: bool) {
+        self.pec.write(pec);
+        #[cfg(not(feature = "cpu_stm32g030"))]
+        self.meta.i2c().CR1.modify(|_,w| w.PECEN().bit(pec));
     }
+
     pub fn arm(&self, flags: u8) {
-        self.error.write(0);
+        // Without DMA, the I2C events alone tell us when we are done.
+        let flags = if M::DMA {flags} else {flags & F_I2C};
+        self.error.write(None);
+        self.reported.write(false);
+        self.armed.write(flags);
         self.outstanding.write(flags);
         barrier();
     }
 
+    /// Unmask TC/TCR, which the ISR masks while the bus is held between
+    /// operations.  Only once the CR2 write starting the next operation has
+    /// cleared them, or they would be taken as events of the new operation.
+    fn unmask_tc(&self) {
+        self.meta.i2c().CR1.modify(|_,w| w.TCIE().set_bit());
+    }
+
     pub fn done(&self) -> bool {self.outstanding.read() == 0}
     pub fn wait(&self) -> Result {
-        while !self.done() {
+        loop {
+            while !self.done() {
+                WFE();
+            }
+            if !self.retry() {
+                return self.result();
+            }
+        }
+    }
+
+    /// Wait for completion, giving up once the tick count `now()` reaches
+    /// `deadline`.  On expiry, the DMA is aborted and the I2C reset.  The tick
+    /// source must generate events (e.g., a SysTick interrupt) to wake us
+    /// from WFE.
+    pub fn wait_until(&self, now: impl Fn() -> u32, deadline: u32) -> Result {
+        while !self.done() || self.retry() {
+            if now().wrapping_sub(deadline) as i32 >= 0 {
+                dbgln!("I2C timeout");
+                self.error_cleanup();
+                self.outstanding.write(0);
+                self.counters.error(I2cError::Timeout);
+                self.finish(Err(I2cError::Timeout));
+                return Err(I2cError::Timeout);
+            }
             WFE();
         }
+        self.result()
+    }
+
+    /// Poll for completion, for use by futures.  The waker is woken from the
+    /// interrupt handlers.  Transactions losing arbitration are not retried.
+    pub fn poll(&self, cx: &mut Context<'_>) -> Poll<Result> {
+        self.waker.register(cx.waker());
+        if self.done() {Poll::Ready(self.result())} else {Poll::Pending}
+    }
+
+    /// Return the result of a finished transaction, cleaning up after an
+    /// error.  It is only reported once, so that a second wait is harmless,
+    /// and just gives `Ok`.
+    fn result(&self) -> Result {
+        let result = self.take_result();
+        if let Err(error) = result {
+            self.cleanup(error);
+        }
+        result
+    }
+
+    /// As `result()`, but leaving any cleanup to the caller.
+    fn take_result(&self) -> Result {
         barrier();
-        if self.error.read() == 0 {
-            Ok(())
+        if self.reported.read() {
+            return Ok(());
+        }
+        let result = match self.error.read() {
+            Some(error) => Err(error),
+            None => Ok(()),
+        };
+        self.error.write(None);
+        self.finish(result);
+        result
+    }
+
+    /// Note that the transaction result has been returned.
+    fn finish(&self, result: Result) {
+        self.reported.write(true);
+        self.trace.finish(result);
+    }
+
+    /// The event counters.
+    pub fn stats(&self) -> Stats {self.counters.get()}
+
+    /// Write the counters, and the trace of recent operations if the
+    /// `i2c_trace` feature is enabled, e.g., to a `debug::Marker`.
+    pub fn dump(&self, w: &mut impl core::fmt::Write) -> core::fmt::Result {
+        stats::dump(w, &self.stats(), &self.trace)
+    }
+    pub fn error_cleanup(&self) {
+        dbgln!("I2C error cleanup");
+        // Clean-up the DMA and reset the I2C.
+        self.reset();
+        self.recover_bus();
+        self.enable();
+    }
+
+    /// Clean up after `error`.  Losing arbitration means that another
+    /// controller owns the bus, so, as in `retry()`, there is no bus recovery
+    /// to disturb it.
+    fn cleanup(&self, error: I2cError) {
+        if error == I2cError::ArbitrationLost {
+            self.reset();
+            self.enable();
         }
         else {
             self.error_cleanup();
-            Err(())
         }
     }
-    pub fn error_cleanup(&self) {
-        dbgln!("I2C error cleanup");
+
+    /// Disable the I2C, and abandon any transaction.
+    fn reset(&self) {
         let i2c = self.meta.i2c();
-        // Clean-up the DMA and reset the I2C.
         i2c.CR1.write(|w| w.PE().clear_bit());
-        self.meta.tx_channel().abort();
-        self.meta.rx_channel().abort();
+        if M::DMA {
+            self.meta.tx_channel().abort();
+            self.meta.rx_channel().abort();
+        }
+        self.machine.reset();
+        self.prefix_len.write(0);
+    }
 
-        self.initialize();
+    /// Set up the I2C and DMA.  Use a const `Timing` to check the timing at
+    /// build time.  For `Speed::FastPlus`, the Fm+ drive needed for 1MHz is
+    /// enabled on the `Meta` SCL and SDA pins.  Where those are not given,
+    /// the G030 and U031 enable it for the whole instance.  If that is not
+    /// possible, the I2C is left disabled.
+    pub fn initialize(&self, timing: Timing)
+            -> core::result::Result<(), NoFastModePlus> {
+        let i2c = self.meta.i2c();
+        // TIMINGR is only writable while disabled.
+        i2c.CR1.write(|w| w.PE().clear_bit());
+        i2c.TIMINGR.write(|w| w.bits(timing.timingr()));
+        if timing.speed == Speed::FastPlus {
+            drive::fast_mode_plus(
+                i2c, self.meta.scl_pin(), self.meta.sda_pin())?;
+        }
+        self.enable();
+        Ok(())
     }
 
-    pub fn initialize(&self) {
+    /// Enable the I2C and DMA, with the timing already configured.
+    fn enable(&self) {
         let i2c = self.meta.i2c();
-        self.meta.rx_channel().read_from(i2c.RXDR.as_ptr() as *const u8, self.meta.rx_muxin());
-        self.meta.tx_channel().writes_to(i2c.TXDR.as_ptr() as *mut u8, self.meta.tx_muxin());
-        i2c.CR1.write(
-            |w|w.TXDMAEN().set_bit().RXDMAEN().set_bit().PE().set_bit()
+        if M::DMA {
+            self.meta.rx_channel().read_from(
+                i2c.RXDR.as_ptr() as *const u8, self.meta.rx_muxin());
+            self.meta.tx_channel().writes_to(
+                i2c.TXDR.as_ptr() as *mut u8, self.meta.tx_muxin());
+        }
+        #[cfg(not(feature = "cpu_stm32g030"))]
+        if let Some(timeout) = self.meta.timeout() {
+            // TIMEOUTA is only writable while the timeout is disabled.
+            i2c.TIMEOUTR.write(|w| w.TIMEOUTA().bits(timeout));
+            i2c.TIMEOUTR.write(
+                |w| w.TIMEOUTA().bits(timeout).TIMOUTEN().set_bit());
+        }
+        i2c.CR1.write(|w| {
+            #[cfg(not(feature = "cpu_stm32g030"))]
+            {
+                // Without SMBHEN, ALERTEN makes us an SMBus device, driving
+                // SMBALERT# rather than listening to it.
+                let alert = self.meta.alert_enable();
+                w.PECEN().bit(self.pec.read()).SMBHEN().bit(alert)
+                    .ALERTEN().bit(alert);
+            }
+            w.TXDMAEN().bit(M::DMA).RXDMAEN().bit(M::DMA).PE().set_bit()
                 .NACKIE().set_bit().ERRIE().set_bit().TCIE().set_bit()
-                .STOPIE().set_bit());
+                .STOPIE().set_bit()});
         barrier();
     }
 }
@@ -186,50 +810,109 @@ macro_rules! implement_i2c_api {($CONTEXT: expr) => {
     pub struct Wait<'a>(core::marker::PhantomData<(&'a [u8], &'a mut [u8])>);
 
     use stm_common::dma::Flat;
+    use stm_common::i2c::Address;
 
     impl<'a> Wait<'a> {
         pub fn new<T: ?Sized>(_ : &'a T) -> Self {Self::default()}
         pub fn defer(self) {core::mem::forget(self);}
-        pub fn wait(self) -> core::result::Result<(), ()> {
+        pub fn wait(self) -> stm_common::i2c::Result {
             let result = CONTEXT.wait();
             core::mem::forget(self);
             result
         }
+        /// Wait, with a deadline, see `I2cContext::wait_until()`.
+        pub fn wait_until(self, now: impl Fn() -> u32, deadline: u32)
+                -> stm_common::i2c::Result {
+            let result = CONTEXT.wait_until(now, deadline);
+            core::mem::forget(self);
+            result
+        }
     }
 
     impl Drop for Wait<'_> {
-        fn drop(&mut self) {let _ = CONTEXT.wait();}
+        fn drop(&mut self) {
+            if !CONTEXT.done() {
+                let _ = CONTEXT.wait();
+            }
+        }
     }
 
-    pub fn write<T: Flat + ?Sized>(addr: u8, data: &T) -> Wait<'_> {
-        CONTEXT.write_start(addr & !1, data.addr(), size_of_val(data), true);
-        Wait::new(data)
+    /// Awaiting the Wait is the async alternative to `wait()`.  As with the
+    /// blocking wait, dropping it early blocks until the transfer is done.
+    impl core::future::Future for Wait<'_> {
+        type Output = stm_common::i2c::Result;
+        fn poll(self: core::pin::Pin<&mut Self>,
+                cx: &mut core::task::Context<'_>)
+                -> core::task::Poll<Self::Output> {
+            CONTEXT.poll(cx)
+        }
     }
 
-    pub fn write_reg<T: Flat + ?Sized>(addr: u8, reg: u8, data: &T)
+    pub fn write<T: Flat + ?Sized>(addr: impl Into<Address>, data: &T)
             -> Wait<'_> {
-        CONTEXT.write_reg_start(addr & !1, reg, data.addr(), size_of_val(data));
+        CONTEXT.write_start(addr, data.addr(), size_of_val(data), true);
         Wait::new(data)
     }
 
-    pub fn read<T: Flat + ?Sized>(addr: u8, data: &mut T) -> Wait<'_> {
-        CONTEXT.read_start(addr | 1, data.addr(), size_of_val(data));
+    pub fn write_reg<T: Flat + ?Sized>(
+        addr: impl Into<Address>, reg: u8, data: &T) -> Wait<'_> {
+        CONTEXT.write_reg_start(addr, reg, data.addr(), size_of_val(data));
         Wait::new(data)
     }
 
-    pub fn read_reg<T: Flat + ?Sized>(addr: u8, reg: u8, data: &mut T)
+    /// As `write_reg()`, with a 16-bit big-endian register address.
+    pub fn write_reg16<T: Flat + ?Sized>(
+        addr: impl Into<Address>, reg: u16, data: &T) -> Wait<'_> {
+        CONTEXT.write_reg_start(addr, reg, data.addr(), size_of_val(data));
+        Wait::new(data)
+    }
+
+    /// As `write_reg()`, with a 32-bit big-endian register address.
+    pub fn write_reg32<T: Flat + ?Sized>(
+        addr: impl Into<Address>, reg: u32, data: &T) -> Wait<'_> {
+        CONTEXT.write_reg_start(addr, reg, data.addr(), size_of_val(data));
+        Wait::new(data)
+    }
+
+    pub fn read<T: Flat + ?Sized>(addr: impl Into<Address>, data: &mut T)
             -> Wait<'_> {
-        CONTEXT.read_reg_start(addr | 1, reg, data.addr(), size_of_val(data));
+        CONTEXT.read_start(addr, data.addr(), size_of_val(data));
+        Wait::new(data)
+    }
+
+    pub fn read_reg<T: Flat + ?Sized>(
+        addr: impl Into<Address>, reg: u8, data: &mut T) -> Wait<'_> {
+        CONTEXT.read_reg_start(addr, reg, data.addr(), size_of_val(data));
+        Wait::new(data)
+    }
+
+    /// As `read_reg()`, with a 16-bit big-endian register address.
+    pub fn read_reg16<T: Flat + ?Sized>(
+        addr: impl Into<Address>, reg: u16, data: &mut T) -> Wait<'_> {
+        CONTEXT.read_reg_start(addr, reg, data.addr(), size_of_val(data));
+        Wait::new(data)
+    }
+
+    /// As `read_reg()`, with a 32-bit big-endian register address.
+    pub fn read_reg32<T: Flat + ?Sized>(
+        addr: impl Into<Address>, reg: u32, data: &mut T) -> Wait<'_> {
+        CONTEXT.read_reg_start(addr, reg, data.addr(), size_of_val(data));
         Wait::new(data)
     }
 
     pub fn write_read<'a, T: Flat + ?Sized, U: Flat + ?Sized>(
-        addr: u8, wdata: &'a T, rdata: &'a mut U) -> Wait<'a> {
+        addr: impl Into<Address>, wdata: &'a T, rdata: &'a mut U)
+            -> Wait<'a> {
         CONTEXT.write_read_start(addr, wdata.addr(), size_of_val(wdata),
                                  rdata.addr(), size_of_val(rdata));
         Wait::new(rdata)
     }
 
+    /// Scan the bus, see `I2cContext::scan()`.
+    pub fn scan(read: bool) -> stm_common::i2c::Result<u128> {
+        CONTEXT.scan(read)
+    }
+
     fn i2c_isr() {
         unsafe {CONTEXT.as_mut()}.isr();
     }
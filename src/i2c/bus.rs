@@ -0,0 +1,313 @@
+//! Sharing one I2C controller between several drivers.
+//!
+//! Transfers are queued, and the interrupt handlers start each one as the
+//! previous finishes, so that thread and interrupt level code can use the bus
+//! without knowing about each other.  Thread code normally goes through a
+//! `Device`, and waits on the `Ticket` it gets back.  Interrupt level code
+//! queues a `Transfer` with a callback.
+
+use core::marker::PhantomData;
+use core::pin::Pin;
+use core::task::{Context, Poll};
+
+use crate::dma::Flat;
+use crate::future::WakerCell;
+use crate::interrupt::free;
+use crate::utils::{WFE, barrier};
+use crate::vcell::VCell;
+
+use super::{Address, I2cContext, I2cError, Meta, OpEnd, Result};
+
+macro_rules!dbgln {($($tt:tt)*) => {if false {crate::dbgln!($($tt)*)}};}
+
+/// A transfer: an optional write, followed by an optional read with a
+/// repeated START.  The buffers must remain valid until completion.
+#[derive(Clone, Copy)]
+pub struct Transfer {
+    pub addr: Address,
+    pub wdata: usize,
+    pub wlen: usize,
+    pub rdata: usize,
+    pub rlen: usize,
+    /// Called from the interrupt handler with the result.  If `None`, the
+    /// result is collected with `I2cBus::take()`, usually via a `Ticket`.
+    pub callback: Option<fn(Result)>,
+}
+
+impl Transfer {
+    const EMPTY: Transfer = Transfer{
+        addr: Address::Seven(0), wdata: 0, wlen: 0, rdata: 0, rlen: 0,
+        callback: None};
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum SlotState {
+    Free,
+    /// Waiting for the bus, with a sequence number giving the queue order.
+    Queued(u32),
+    Active,
+    /// Finished, waiting for the result to be taken.
+    Done,
+}
+
+struct Slot {
+    state: VCell<SlotState>,
+    transfer: VCell<Transfer>,
+    result: VCell<Result>,
+    waker: WakerCell,
+}
+
+impl Slot {
+    const fn new() -> Slot {
+        Slot{state: VCell::new(SlotState::Free),
+             transfer: VCell::new(Transfer::EMPTY),
+             result: VCell::new(Ok(())), waker: WakerCell::new()}
+    }
+}
+
+/// An I2C context with a queue of up to `N` transfers.  The interrupt
+/// handlers must call `isr()` and `dma_isr()` here, in place of those of the
+/// context.  Nothing else should start transfers on the context directly.
+pub struct I2cBus<M, const N: usize> {
+    pub context: I2cContext<M>,
+    slots: [Slot; N],
+    /// Sequence number for the next queued transfer.
+    seq: VCell<u32>,
+    /// The slot on the bus, or `N` if idle.
+    active: VCell<usize>,
+    /// Cleaning up after an error, before starting the next transfer.
+    recovering: VCell<bool>,
+}
+
+impl<M: const Default, const N: usize> const Default for I2cBus<M, N> {
+    fn default() -> Self {
+        Self{context: I2cContext::default(), slots: [const {Slot::new()}; N],
+             seq: VCell::new(0), active: VCell::new(N),
+             recovering: VCell::new(false)}
+    }
+}
+
+impl<M: Meta, const N: usize> I2cBus<M, N> {
+    pub fn isr(&mut self) {
+        self.context.isr();
+        self.complete();
+    }
+
+    pub fn dma_isr(&mut self, flag: u8, error: bool) {
+        self.context.dma_isr(flag, error);
+        self.complete();
+    }
+
+    /// A handle for the device at `addr`.
+    pub fn device(&self, addr: impl Into<Address>) -> Device<'_, M, N> {
+        Device{bus: self, addr: addr.into()}
+    }
+
+    /// Queue a transfer, returning its slot number, or `None` if the queue is
+    /// full.  Usable from interrupt handlers.
+    pub fn queue(&self, transfer: Transfer) -> Option<usize> {
+        // Results are taken in the ISR, where there is no retrying.
+        const {assert!(M::ARBITRATION_RETRIES == 0,
+                       "I2cBus cannot retry lost arbitration")};
+        free(|| {
+            let slot = self.slots.iter().position(
+                |s| s.state.read() == SlotState::Free)?;
+            let seq = self.seq.read();
+            self.seq.write(seq.wrapping_add(1));
+            self.slots[slot].transfer.write(transfer);
+            self.slots[slot].state.write(SlotState::Queued(seq));
+            dbgln!("I2C bus queue {slot} seq {seq}");
+            if self.active.read() == N {
+                self.start_next();
+            }
+            Some(slot)
+        })
+    }
+
+    /// Has the transfer in `slot` finished?
+    pub fn done(&self, slot: usize) -> bool {
+        self.slots[slot].state.read() == SlotState::Done
+    }
+
+    /// Collect the result of a finished transfer queued without a callback,
+    /// freeing its slot.
+    pub fn take(&self, slot: usize) -> Result {
+        barrier();
+        let result = self.slots[slot].result.read();
+        self.slots[slot].state.write(SlotState::Free);
+        result
+    }
+
+    /// Register a waker for the transfer in `slot`, and check if it is done.
+    pub fn poll(&self, slot: usize, cx: &mut Context<'_>) -> Poll<()> {
+        self.slots[slot].waker.register(cx.waker());
+        if self.done(slot) {Poll::Ready(())} else {Poll::Pending}
+    }
+
+    /// If the transfer on the bus has finished, report it and start the next.
+    fn complete(&self) {
+        let Some(error) = free(|| self.report()) else {
+            return;
+        };
+        // Bus recovery busy-waits, for up to milliseconds, so not with
+        // interrupts disabled.  The bus stays ours meanwhile, as `active` is
+        // not `N`.  After losing arbitration, the bus is not ours to recover.
+        if error != I2cError::ArbitrationLost {
+            self.context.recover_bus();
+        }
+        free(|| {
+            self.context.enable();
+            self.recovering.write(false);
+            self.start_next();
+        });
+    }
+
+    /// Report a finished transfer, and start the next.  On an error, the I2C
+    /// is left in reset, and the error returned, for `complete()` to finish
+    /// the cleanup.  Interrupts must be disabled.
+    fn report(&self) -> Option<I2cError> {
+        let active = self.active.read();
+        if active == N || !self.context.done() || self.recovering.read() {
+            return None;
+        }
+        let slot = &self.slots[active];
+        let result = self.context.take_result();
+        dbgln!("I2C bus done {active} {result:?}");
+        if result.is_err() {
+            self.context.reset();
+            self.recovering.write(true);
+        }
+        if let Some(callback) = slot.transfer.read().callback {
+            slot.state.write(SlotState::Free);
+            callback(result);
+        }
+        else {
+            slot.result.write(result);
+            barrier();
+            slot.state.write(SlotState::Done);
+            slot.waker.wake();
+        }
+        match result {
+            Err(error) => Some(error),
+            Ok(()) => {
+                self.start_next();
+                None
+            },
+        }
+    }
+
+    /// Start the oldest queued transfer, if any.  Interrupts must be disabled.
+    fn start_next(&self) {
+        let seq = self.seq.read();
+        let next = self.slots.iter().enumerate().filter_map(
+            |(i, s)| match s.state.read() {
+                // Older transfers wrap further below `seq`.
+                SlotState::Queued(q) => Some((q.wrapping_sub(seq), i)),
+                _ => None,
+            }).min();
+        let Some((_, i)) = next else {
+            self.active.write(N);
+            return;
+        };
+        self.active.write(i);
+        self.slots[i].state.write(SlotState::Active);
+        let t = self.slots[i].transfer.read();
+        let context = &self.context;
+        match (t.wlen, t.rlen) {
+            (0, 0) => context.operation_start(
+                t.addr, false, 0, 0, true, OpEnd::Stop),
+            (_, 0) => context.write_start(t.addr, t.wdata, t.wlen, true),
+            (0, _) => context.read_start(t.addr, t.rdata, t.rlen),
+            _ => context.write_read_start(
+                t.addr, t.wdata, t.wlen, t.rdata, t.rlen),
+        }
+    }
+}
+
+/// A device on a shared bus.
+pub struct Device<'a, M, const N: usize> {
+    bus: &'a I2cBus<M, N>,
+    pub addr: Address,
+}
+
+impl<'a, M: Meta, const N: usize> Device<'a, M, N> {
+    pub fn write<'b, T: Flat + ?Sized>(&self, data: &'b T) -> Ticket<'b, M, N>
+            where 'a: 'b {
+        self.submit(data.addr(), size_of_val(data), 0, 0)
+    }
+
+    pub fn read<'b, T: Flat + ?Sized>(&self, data: &'b mut T)
+            -> Ticket<'b, M, N> where 'a: 'b {
+        self.submit(0, 0, data.addr(), size_of_val(data))
+    }
+
+    pub fn write_read<'b, T: Flat + ?Sized, U: Flat + ?Sized>(
+        &self, wdata: &'b T, rdata: &'b mut U) -> Ticket<'b, M, N>
+            where 'a: 'b {
+        self.submit(wdata.addr(), size_of_val(wdata),
+                    rdata.addr(), size_of_val(rdata))
+    }
+
+    /// Queue a transfer, waiting for a free slot if needed.  Don't use this
+    /// from interrupt handlers, as the queue may never drain.
+    fn submit<'b>(&self, wdata: usize, wlen: usize, rdata: usize, rlen: usize)
+            -> Ticket<'b, M, N> where 'a: 'b {
+        let transfer = Transfer{
+            addr: self.addr, wdata, wlen, rdata, rlen, callback: None};
+        loop {
+            if let Some(slot) = self.bus.queue(transfer) {
+                return Ticket{bus: self.bus, slot, _data: PhantomData};
+            }
+            WFE();
+        }
+    }
+}
+
+/// A queued transfer.  Wait for it, or await it.  As with `Wait`, dropping
+/// it early blocks until the hardware is finished with the buffers.
+#[must_use]
+pub struct Ticket<'a, M: Meta, const N: usize> {
+    bus: &'a I2cBus<M, N>,
+    /// The queue slot, or `N` once the result is taken.
+    slot: usize,
+    _data: PhantomData<(&'a [u8], &'a mut [u8])>,
+}
+
+impl<M: Meta, const N: usize> Ticket<'_, M, N> {
+    pub fn done(&self) -> bool {self.bus.done(self.slot)}
+
+    pub fn wait(mut self) -> Result {
+        while !self.done() {
+            WFE();
+        }
+        self.take()
+    }
+
+    fn take(&mut self) -> Result {
+        let result = self.bus.take(self.slot);
+        self.slot = N;
+        result
+    }
+}
+
+impl<M: Meta, const N: usize> Future for Ticket<'_, M, N> {
+    type Output = Result;
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result> {
+        let this = self.get_mut();
+        match this.bus.poll(this.slot, cx) {
+            Poll::Ready(()) => Poll::Ready(this.take()),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+impl<M: Meta, const N: usize> Drop for Ticket<'_, M, N> {
+    fn drop(&mut self) {
+        if self.slot != N {
+            while !self.done() {
+                WFE();
+            }
+            let _ = self.take();
+        }
+    }
+}
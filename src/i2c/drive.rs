@@ -0,0 +1,83 @@
+//! Fast-mode Plus drive.  At 1MHz, the SCL and SDA pins need the 20mA sink of
+//! the Fm+ I/Os, which is enabled in SYSCFG.CFGR1, or SBS.PMCR on the H503.
+//! Some pins have their own enable bit.  On the G030 and U031, an instance
+//! bit covers whatever pins the I2C is using.
+
+use crate::stm32::{GPIOB, i2c1::RegisterBlock};
+
+use super::recovery::Pin;
+
+/// The Fm+ enable bit for a pin, if it has one.
+#[cfg(not(feature = "cpu_stm32h503"))]
+fn pin_bit(pin: &Pin) -> Option<u32> {
+    let port = core::ptr::from_ref(pin.gpio).addr();
+    if port == crate::stm32::GPIOA::ptr().addr() {
+        match pin.pin {
+            9 => Some(1 << 22),
+            10 => Some(1 << 23),
+            _ => None,
+        }
+    }
+    else if port == GPIOB::ptr().addr() && (6 ..= 9).contains(&pin.pin) {
+        Some(1 << 16 + pin.pin - 6)
+    }
+    else {
+        None
+    }
+}
+
+#[cfg(feature = "cpu_stm32h503")]
+fn pin_bit(pin: &Pin) -> Option<u32> {
+    let port = core::ptr::from_ref(pin.gpio).addr();
+    if port == GPIOB::ptr().addr() && (6 ..= 8).contains(&pin.pin) {
+        Some(1 << 16 + pin.pin - 6)
+    }
+    else {
+        None
+    }
+}
+
+/// The Fm+ enable bit covering all the pins of an instance, if there is one.
+#[cfg(not(feature = "cpu_stm32h503"))]
+fn instance_bit(i2c: *const RegisterBlock) -> Option<u32> {
+    use crate::stm32::{I2C1, I2C2};
+    if i2c == I2C1::ptr() {Some(1 << 20)}
+    else if i2c == I2C2::ptr() {Some(1 << 21)}
+    else {None}
+}
+
+#[cfg(feature = "cpu_stm32h503")]
+fn instance_bit(_: *const RegisterBlock) -> Option<u32> {None}
+
+/// An I2C pin has no Fm+ drive, and no instance bit covers it.  The H503 has
+/// no instance bits, so both pins must be given, and be PB6 to PB8.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct NoFastModePlus;
+
+/// Enable the Fm+ drive for the I2C pins.  Pins not given, or without their
+/// own bit, are covered by the instance bit.  If there is neither, nothing is
+/// enabled.
+pub fn fast_mode_plus(i2c: &RegisterBlock, scl: Option<Pin>, sda: Option<Pin>)
+        -> Result<(), NoFastModePlus> {
+    let mut bits = 0;
+    for pin in [scl, sda] {
+        bits |= pin.as_ref().and_then(pin_bit).or_else(|| instance_bit(i2c))
+            .ok_or(NoFastModePlus)?;
+    }
+    let rcc = unsafe {&*crate::stm32::RCC::ptr()};
+    #[cfg(not(feature = "cpu_stm32h503"))]
+    {
+        // APBENR2.SYSCFGEN.
+        rcc.APBENR2.modify(|r,w| w.bits(r.bits() | 1));
+        let syscfg = unsafe {&*crate::stm32::SYSCFG::ptr()};
+        syscfg.CFGR1.modify(|r,w| w.bits(r.bits() | bits));
+    }
+    #[cfg(feature = "cpu_stm32h503")]
+    {
+        // APB3ENR.SBSEN.
+        rcc.APB3ENR.modify(|r,w| w.bits(r.bits() | 2));
+        let sbs = unsafe {&*crate::stm32::SBS::ptr()};
+        sbs.PMCR.modify(|r,w| w.bits(r.bits() | bits));
+    }
+    Ok(())
+}
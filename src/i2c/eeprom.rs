@@ -0,0 +1,176 @@
+//! 24Cxx / M24xxx I2C EEPROMs.
+//!
+//! Writes are split at page boundaries, and each page is followed by ACK
+//! polling until the write cycle completes.  Reads may be any length.  For the
+//! 24C04 to 24C16, and the 24CM01 and 24CM02, the memory address bits that do
+//! not fit in the address bytes go in the device address.
+
+use super::{I2cContext, I2cError, Meta, RegAddr, Result};
+
+/// The size and organisation of an EEPROM.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Geometry {
+    /// Capacity in bytes.
+    pub size: u32,
+    /// Write page size in bytes.
+    pub page: u16,
+    /// Number of memory address bytes, 1 or 2.
+    pub addr_bytes: u8,
+}
+
+impl Geometry {
+    pub const C01  : Geometry = Geometry::new(128, 8, 1);
+    pub const C02  : Geometry = Geometry::new(256, 8, 1);
+    pub const C04  : Geometry = Geometry::new(512, 16, 1);
+    pub const C08  : Geometry = Geometry::new(1024, 16, 1);
+    pub const C16  : Geometry = Geometry::new(2048, 16, 1);
+    pub const C32  : Geometry = Geometry::new(4096, 32, 2);
+    pub const C64  : Geometry = Geometry::new(8192, 32, 2);
+    pub const C128 : Geometry = Geometry::new(16384, 64, 2);
+    pub const C256 : Geometry = Geometry::new(32768, 64, 2);
+    pub const C512 : Geometry = Geometry::new(65536, 128, 2);
+    pub const CM01 : Geometry = Geometry::new(131072, 256, 2);
+    pub const CM02 : Geometry = Geometry::new(262144, 256, 2);
+
+    pub const fn new(size: u32, page: u16, addr_bytes: u8) -> Geometry {
+        assert!(addr_bytes == 1 || addr_bytes == 2);
+        assert!(page.is_power_of_two() && page <= 256);
+        Geometry{size, page, addr_bytes}
+    }
+
+    /// The memory covered by one device address.
+    const fn block(&self) -> u32 {1 << 8 * self.addr_bytes}
+}
+
+pub struct Eeprom<'a, M> {
+    context: &'a I2cContext<M>,
+    /// Device address in 8-bit form, including any chip enable bits.
+    addr: u8,
+    geometry: Geometry,
+    /// The longest write cycle time, tWR, in µs.  ACK polling gives up after
+    /// at least this long.
+    pub write_time: u32,
+}
+
+/// Delay between ACK polls, in µs.
+const POLL_INTERVAL: u32 = 50;
+
+impl<'a, M: Meta> Eeprom<'a, M> {
+    /// `addr` is the 8-bit device address, usually 0xa0 plus the chip enable
+    /// bits.
+    pub fn new(context: &'a I2cContext<M>, addr: u8, geometry: Geometry)
+            -> Self {
+        Self{context, addr, geometry, write_time: 10_000}
+    }
+
+    pub fn geometry(&self) -> Geometry {self.geometry}
+
+    /// Read `data.len()` bytes starting at `offset`.
+    pub fn read(&self, offset: u32, data: &mut [u8]) -> Result {
+        assert!(offset as usize + data.len() <= self.geometry.size as usize,
+                "EEPROM read past the end");
+        let block = self.geometry.block();
+        let mut done = 0;
+        while done < data.len() {
+            let offset = offset + done as u32;
+            let len = ((block - offset % block) as usize)
+                .min(data.len() - done);
+            let chunk = &mut data[done .. done + len];
+            self.read_at(self.device(offset), offset, chunk)?;
+            done += len;
+        }
+        Ok(())
+    }
+
+    /// Write `data` starting at `offset`, one page at a time, waiting for each
+    /// write cycle to complete.
+    pub fn write(&self, offset: u32, data: &[u8]) -> Result {
+        assert!(offset as usize + data.len() <= self.geometry.size as usize,
+                "EEPROM write past the end");
+        let page = self.geometry.page as u32;
+        let mut done = 0;
+        while done < data.len() {
+            let offset = offset + done as u32;
+            let len = ((page - offset % page) as usize).min(data.len() - done);
+            let chunk = &data[done .. done + len];
+            self.write_at(self.device(offset), offset, chunk)?;
+            done += len;
+        }
+        Ok(())
+    }
+
+    /// Wait for a write cycle to finish.  The device does not acknowledge its
+    /// address until it is done.
+    pub fn wait_ready(&self) -> Result {
+        // The polls take time on the bus too, so this waits at least tWR.
+        for _ in 0 ..= self.write_time / POLL_INTERVAL {
+            if self.context.ack_poll(self.addr >> 1)? {
+                return Ok(());
+            }
+            // Half clocks at 100kHz, at least 5µs each.
+            for _ in 0 .. POLL_INTERVAL / 5 {
+                self.context.half_clock();
+            }
+        }
+        Err(I2cError::Timeout)
+    }
+
+    /// Read from the identification page of an M24xxx-D.
+    pub fn read_id(&self, offset: u8, data: &mut [u8]) -> Result {
+        assert!(offset as usize + data.len() <= self.geometry.page as usize,
+                "EEPROM ID page read past the end");
+        self.read_at(self.id_device(), offset as u32, data)
+    }
+
+    /// Write to the identification page of an M24xxx-D.  Fails with an
+    /// `AddressNack` or `DataNack` if the page is locked.
+    pub fn write_id(&self, offset: u8, data: &[u8]) -> Result {
+        assert!(offset as usize + data.len() <= self.geometry.page as usize,
+                "EEPROM ID page write past the end");
+        self.write_at(self.id_device(), offset as u32, data)
+    }
+
+    /// Permanently lock the identification page of an M24xxx-D.  The lock is
+    /// a write with the top memory address bit set, i.e., A10 for parts with
+    /// two address bytes, and A7 for those with one.
+    pub fn lock_id(&self) -> Result {
+        let lock = if self.geometry.addr_bytes == 2 {0x400} else {0x80};
+        self.write_at(self.id_device(), lock, &[0x02])
+    }
+
+    /// The device address for the memory at `offset`.
+    fn device(&self, offset: u32) -> u8 {
+        let high = (offset / self.geometry.block()) as u8;
+        self.addr | high << 1
+    }
+
+    /// The device address of the identification page.
+    fn id_device(&self) -> u8 {self.addr & 0x0e | 0xb0}
+
+    fn read_at(&self, device: u8, offset: u32, data: &mut [u8]) -> Result {
+        let (ptr, len) = (data.as_mut_ptr().addr(), data.len());
+        if self.geometry.addr_bytes == 1 {
+            self.context.read_reg_start(device, offset as u8, ptr, len);
+        }
+        else {
+            self.context.read_reg_start(device, offset as u16, ptr, len);
+        }
+        self.context.wait()
+    }
+
+    fn write_at(&self, device: u8, offset: u32, data: &[u8]) -> Result {
+        if self.geometry.addr_bytes == 1 {
+            self.write_reg(device, offset as u8, data)?;
+        }
+        else {
+            self.write_reg(device, offset as u16, data)?;
+        }
+        self.wait_ready()
+    }
+
+    fn write_reg<R: RegAddr>(&self, device: u8, reg: R, data: &[u8]) -> Result {
+        self.context.write_reg_start(
+            device, reg, data.as_ptr().addr(), data.len());
+        self.context.wait()
+    }
+}
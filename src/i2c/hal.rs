@@ -0,0 +1,165 @@
+//! embedded-hal 1.0 I2C traits, implemented for `&I2cContext`.
+//!
+//! Seven bit addresses are as used by embedded-hal, not the 8-bit form used by
+//! the rest of this crate.
+
+use embedded_hal::i2c::{
+    Error, ErrorKind, ErrorType, NoAcknowledgeSource, Operation,
+    SevenBitAddress, TenBitAddress};
+
+use super::{Address, I2cContext, I2cError, Meta, OpEnd, Result};
+
+impl Error for I2cError {
+    fn kind(&self) -> ErrorKind {
+        match *self {
+            I2cError::AddressNack =>
+                ErrorKind::NoAcknowledge(NoAcknowledgeSource::Address),
+            I2cError::DataNack(_) =>
+                ErrorKind::NoAcknowledge(NoAcknowledgeSource::Data),
+            I2cError::ArbitrationLost => ErrorKind::ArbitrationLoss,
+            I2cError::BusError => ErrorKind::Bus,
+            I2cError::Overrun => ErrorKind::Overrun,
+            _ => ErrorKind::Other,
+        }
+    }
+}
+
+impl<M: Meta> ErrorType for &I2cContext<M> {
+    type Error = I2cError;
+}
+
+impl<M: Meta> embedded_hal::i2c::I2c<SevenBitAddress> for &I2cContext<M> {
+    fn transaction(&mut self, address: u8, operations: &mut [Operation<'_>])
+            -> Result {
+        transaction(*self, Address::Seven(address << 1), operations)
+    }
+}
+
+impl<M: Meta> embedded_hal::i2c::I2c<TenBitAddress> for &I2cContext<M> {
+    fn transaction(&mut self, address: u16, operations: &mut [Operation<'_>])
+            -> Result {
+        transaction(*self, Address::Ten(address), operations)
+    }
+}
+
+impl<M: Meta> embedded_hal_async::i2c::I2c<SevenBitAddress>
+        for &I2cContext<M> {
+    async fn transaction(&mut self, address: u8,
+                         operations: &mut [Operation<'_>]) -> Result {
+        let address = Address::Seven(address << 1);
+        transaction_async(*self, address, operations).await
+    }
+}
+
+impl<M: Meta> embedded_hal_async::i2c::I2c<TenBitAddress>
+        for &I2cContext<M> {
+    async fn transaction(&mut self, address: u16,
+                         operations: &mut [Operation<'_>]) -> Result {
+        transaction_async(*self, Address::Ten(address), operations).await
+    }
+}
+
+fn transaction<M: Meta>(context: &I2cContext<M>, address: Address,
+                        operations: &mut [Operation<'_>]) -> Result {
+    if let Some(step) = probe(operations) {
+        start(context, address, &step);
+        return context.wait();
+    }
+    for i in 0 .. operations.len() {
+        if let Some(step) = step(operations, i) {
+            start(context, address, &step);
+            context.wait()?;
+        }
+    }
+    Ok(())
+}
+
+async fn transaction_async<M: Meta>(context: &I2cContext<M>, address: Address,
+                                    operations: &mut [Operation<'_>])
+        -> Result {
+    if let Some(step) = probe(operations) {
+        start(context, address, &step);
+        return Completion(context).await;
+    }
+    for i in 0 .. operations.len() {
+        if let Some(step) = step(operations, i) {
+            start(context, address, &step);
+            Completion(context).await?;
+        }
+    }
+    Ok(())
+}
+
+/// One hardware operation of a transaction.
+struct Step {
+    read: bool,
+    data: usize,
+    len: usize,
+    start: bool,
+    end: OpEnd,
+}
+
+fn start<M: Meta>(context: &I2cContext<M>, address: Address, step: &Step) {
+    context.operation_start(address, step.read, step.data, step.len,
+                            step.start, step.end);
+}
+
+fn info(op: &Operation) -> (bool, usize, usize) {
+    match op {
+        Operation::Read (b) => (true,  b.as_ptr().addr(), b.len()),
+        Operation::Write(b) => (false, b.as_ptr().addr(), b.len()),
+    }
+}
+
+/// If there is no data at all, then just address the device with a zero
+/// length write.
+fn probe(ops: &[Operation]) -> Option<Step> {
+    if ops.iter().all(|op| info(op).2 == 0) {
+        Some(Step{read: false, data: 0, len: 0, start: true, end: OpEnd::Stop})
+    }
+    else {
+        None
+    }
+}
+
+/// Work out how operation `i` fits with its neighbours.  Adjacent operations
+/// in the same direction are merged, without a repeated START.  Empty
+/// operations are skipped.
+fn step(ops: &[Operation], i: usize) -> Option<Step> {
+    let (read, data, len) = info(&ops[i]);
+    if len == 0 {
+        return None;
+    }
+    let direction = |op: &Operation| {
+        let (read, _, len) = info(op);
+        if len != 0 {Some(read)} else {None}
+    };
+    let prev = ops[.. i].iter().rev().find_map(direction);
+    let next = ops[i + 1 ..].iter().find_map(direction);
+    let end = match next {
+        None => OpEnd::Stop,
+        Some(r) if r == read => OpEnd::Continue,
+        Some(_) => OpEnd::Restart,
+    };
+    Some(Step{read, data, len, start: prev != Some(read), end})
+}
+
+/// Future for completion of an operation.  If dropped early, we block until
+/// the hardware is finished with the buffers.
+struct Completion<'a, M: Meta>(&'a I2cContext<M>);
+
+impl<M: Meta> Future for Completion<'_, M> {
+    type Output = Result;
+    fn poll(self: core::pin::Pin<&mut Self>,
+            cx: &mut core::task::Context<'_>) -> core::task::Poll<Result> {
+        self.0.poll(cx)
+    }
+}
+
+impl<M: Meta> Drop for Completion<'_, M> {
+    fn drop(&mut self) {
+        if !self.0.done() {
+            let _ = self.0.wait();
+        }
+    }
+}
@@ -0,0 +1,118 @@
+//! I2C bus recovery.  A target that was reset mid-byte may be left holding
+//! SDA low; clocking SCL by hand until it lets go, and then sending a STOP,
+//! frees the bus.
+
+use crate::utils::nothing;
+
+use super::{I2cContext, Meta};
+
+macro_rules!dbgln {($($tt:tt)*) => {if false {crate::dbgln!($($tt)*)}};}
+
+pub type GPIO = crate::stm32::gpioa::RegisterBlock;
+
+/// A GPIO pin carrying SCL or SDA.
+#[derive(Clone, Copy)]
+pub struct Pin {
+    pub gpio: &'static GPIO,
+    /// Pin number within the port.
+    pub pin: u8,
+    /// Alternate function number for the I2C.
+    pub af: u8,
+}
+
+impl Pin {
+    fn set(&self, high: bool) {
+        let bit = if high {1 << self.pin} else {1 << self.pin + 16};
+        self.gpio.BSRR.write(|w| w.bits(bit));
+    }
+    fn get(&self) -> bool {
+        self.gpio.IDR.read().bits() & 1 << self.pin != 0
+    }
+    /// Set MODER to 1 (output) or 2 (alternate function).
+    fn mode(&self, mode: u32) {
+        let shift = self.pin * 2;
+        self.gpio.MODER.modify(
+            |r,w| w.bits(r.bits() & !(3 << shift) | mode << shift));
+    }
+    /// Switch to open-drain GPIO output, released high.
+    fn to_gpio(&self) {
+        self.set(true);
+        self.gpio.OTYPER.modify(|r,w| w.bits(r.bits() | 1 << self.pin));
+        self.mode(1);
+    }
+    /// Hand the pin back to the I2C.
+    fn to_af(&self) {
+        let shift = self.pin % 8 * 4;
+        let af = self.af as u32;
+        if self.pin < 8 {
+            self.gpio.AFRL.modify(
+                |r,w| w.bits(r.bits() & !(15 << shift) | af << shift));
+        }
+        else {
+            self.gpio.AFRH.modify(
+                |r,w| w.bits(r.bits() & !(15 << shift) | af << shift));
+        }
+        self.mode(2);
+    }
+}
+
+impl<M: Meta> I2cContext<M> {
+    /// Free a stuck bus by clocking SCL until SDA is released, followed by a
+    /// STOP.  The I2C should be disabled.  Does nothing unless the `Meta`
+    /// provides the pins, or if both lines are already high.
+    pub fn recover_bus(&self) {
+        let (Some(scl), Some(sda)) = (self.meta.scl_pin(), self.meta.sda_pin())
+        else {
+            return;
+        };
+        if scl.get() && sda.get() {
+            return;                     // Bus is idle, nothing to do.
+        }
+        dbgln!("I2C bus recovery, SCL {} SDA {}", scl.get(), sda.get());
+        self.counters.recovery();
+        scl.to_gpio();
+        sda.to_gpio();
+        self.half_clock();
+
+        for _ in 0 .. 9 {
+            if sda.get() {
+                break;
+            }
+            scl.set(false);
+            self.half_clock();
+            self.scl_release(&scl);
+        }
+
+        // STOP: SDA rises while SCL is high.
+        scl.set(false);
+        self.half_clock();
+        sda.set(false);
+        self.half_clock();
+        self.scl_release(&scl);
+        sda.set(true);
+        self.half_clock();
+        dbgln!("I2C bus recovery done, SDA {}", sda.get());
+
+        scl.to_af();
+        sda.to_af();
+    }
+
+    /// Release SCL, allowing a target to stretch the clock for a while.
+    fn scl_release(&self, scl: &Pin) {
+        scl.set(true);
+        for _ in 0 .. 100 {
+            if scl.get() {
+                break;
+            }
+            self.half_clock();
+        }
+        self.half_clock();
+    }
+
+    /// Half a clock period at 100kHz or less.
+    pub(super) fn half_clock(&self) {
+        for _ in 0 .. M::CPU_FREQ / 200000 {
+            nothing();
+        }
+    }
+}
@@ -0,0 +1,122 @@
+//! Retrying transactions that lose arbitration.
+//!
+//! On a multi-master bus, losing arbitration is routine.  If the `Meta` asks
+//! for it, `wait()` and `wait_until()` restart the transaction once the bus
+//! is free again, after a backoff growing with each attempt.  The bus free
+//! wait and backoff block, so `poll()` does not retry.  Only whole
+//! transactions are retried: an operation continuing a transaction that holds
+//! the bus reports `ArbitrationLost` as usual.
+
+use crate::utils::barrier;
+use crate::vcell::VCell;
+
+use super::{Address, I2cContext, I2cError, Meta, OpEnd, state::State};
+
+macro_rules!dbgln {($($tt:tt)*) => {if false {crate::dbgln!($($tt)*)}};}
+
+/// Half clocks to wait for the bus to go free before giving up, about 10ms.
+const BUSY_LIMIT: u32 = 2000;
+
+/// The start function call that began the current transaction.
+#[derive(Clone, Copy)]
+#[derive_const(Default)]
+pub enum Replay {
+    #[default]
+    None,
+    ReadReg{addr: Address, reg: u32, reg_len: u8, data: usize, len: usize},
+    Read{addr: Address, data: usize, len: usize},
+    WriteReg{addr: Address, reg: u32, reg_len: u8, data: usize, len: usize},
+    Write{addr: Address, data: usize, len: usize},
+    WriteRead{addr: Address, wdata: usize, wlen: usize,
+              rdata: usize, rlen: usize},
+    Operation{addr: Address, read: bool, data: usize, len: usize},
+}
+
+/// Retry state, kept in the `I2cContext`.
+#[derive_const(Default)]
+pub struct Retry {
+    replay: VCell<Replay>,
+    /// Retries made so far of the current transaction.
+    attempt: VCell<u8>,
+    /// Set while `replay()` restarts the transaction.
+    replaying: VCell<bool>,
+}
+
+impl<M: Meta> I2cContext<M> {
+    /// Note the start of an operation, for `retry()` and the transaction
+    /// count.  Must be called before the state machine is started.
+    pub(super) fn record(&self, replay: Replay) {
+        if self.retry.replaying.read() {
+            return;
+        }
+        let held = self.machine.state() == State::Hold;
+        if !held {
+            self.counters.start();
+        }
+        self.retry.replay.write(if held {Replay::None} else {replay});
+        self.retry.attempt.write(0);
+    }
+
+    /// If the finished transaction lost arbitration, and the retry policy
+    /// allows, start it again and return true.
+    pub(super) fn retry(&self) -> bool {
+        barrier();
+        let attempt = self.retry.attempt.read();
+        let replay = self.retry.replay.read();
+        if self.error.read() != Some(I2cError::ArbitrationLost)
+            || attempt >= M::ARBITRATION_RETRIES
+            || matches!(replay, Replay::None) {
+            return false;
+        }
+        dbgln!("I2C arbitration lost, retry {}", attempt + 1);
+        // Another controller owns the bus, so just reset, without the bus
+        // recovery of `error_cleanup()`.
+        self.reset();
+        self.enable();
+        if !self.bus_free() {
+            return false;
+        }
+        self.trace.finish(Err(I2cError::ArbitrationLost));
+        self.counters.retry();
+        // Back off for longer each time, from 40µs up to 640µs.
+        for _ in 0 .. 8 << attempt.min(4) {
+            self.half_clock();
+        }
+        self.error.write(None);
+        self.retry.replaying.write(true);
+        self.replay(replay);
+        self.retry.replaying.write(false);
+        self.retry.attempt.write(attempt + 1);
+        true
+    }
+
+    /// Wait for the other controller to finish with the bus.
+    fn bus_free(&self) -> bool {
+        let i2c = self.meta.i2c();
+        for _ in 0 .. BUSY_LIMIT {
+            if !i2c.ISR.read().BUSY().bit() {
+                return true;
+            }
+            self.half_clock();
+        }
+        dbgln!("I2C bus still busy, giving up");
+        false
+    }
+
+    fn replay(&self, replay: Replay) {
+        match replay {
+            Replay::None => (),
+            Replay::ReadReg{addr, reg, reg_len, data, len} =>
+                self.read_reg_op(addr, reg, reg_len, data, len),
+            Replay::Read{addr, data, len} => self.read_start(addr, data, len),
+            Replay::WriteReg{addr, reg, reg_len, data, len} =>
+                self.write_reg_op(addr, reg, reg_len, data, len),
+            Replay::Write{addr, data, len} =>
+                self.write_start(addr, data, len, true),
+            Replay::WriteRead{addr, wdata, wlen, rdata, rlen} =>
+                self.write_read_start(addr, wdata, wlen, rdata, rlen),
+            Replay::Operation{addr, read, data, len} =>
+                self.operation_start(addr, read, data, len, true, OpEnd::Stop),
+        }
+    }
+}
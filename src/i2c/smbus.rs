@@ -0,0 +1,158 @@
+//! SMBus protocols, layered over the `I2cContext` DMA transfers.
+//!
+//! Addresses are in the 8-bit form used by the rest of this crate.  All the
+//! calls block until the transaction is done.  PEC, if enabled, is generated
+//! and checked by the hardware.
+
+use super::{Address, I2cContext, I2cError, Meta, OpEnd, Result};
+
+/// The Alert Response Address, in 8-bit form.
+pub const ARA: u8 = 0x0c << 1;
+
+/// Maximum block length for block reads and writes.
+pub const BLOCK_MAX: usize = 255;
+
+pub struct SmBus<'a, M> {
+    context: &'a I2cContext<M>,
+    /// Send and check a Packet Error Code on each transaction.
+    pub pec: bool,
+}
+
+impl<'a, M: Meta> SmBus<'a, M> {
+    pub fn new(context: &'a I2cContext<M>, pec: bool) -> Self {
+        Self{context, pec}
+    }
+
+    /// Quick Command: just the address, with `read` as the R/W bit.  There is
+    /// never a PEC.  The hardware cannot do a zero length read, so a read
+    /// clocks in one byte and discards it.
+    pub fn quick(&self, addr: u8, read: bool) -> Result {
+        let mut byte = 0u8;
+        let data = core::ptr::from_mut(&mut byte).addr();
+        self.op(addr, read, data, read as usize, true, OpEnd::Stop)
+    }
+
+    pub fn send_byte(&self, addr: u8, byte: u8) -> Result {
+        self.write(addr, &[byte])
+    }
+
+    pub fn receive_byte(&self, addr: u8) -> Result<u8> {
+        let mut byte = [0];
+        self.read(addr, &[], &mut byte)?;
+        Ok(byte[0])
+    }
+
+    pub fn write_byte(&self, addr: u8, cmd: u8, byte: u8) -> Result {
+        self.write(addr, &[cmd, byte])
+    }
+
+    pub fn read_byte(&self, addr: u8, cmd: u8) -> Result<u8> {
+        let mut byte = [0];
+        self.read(addr, &[cmd], &mut byte)?;
+        Ok(byte[0])
+    }
+
+    pub fn write_word(&self, addr: u8, cmd: u8, word: u16) -> Result {
+        let [lo, hi] = word.to_le_bytes();
+        self.write(addr, &[cmd, lo, hi])
+    }
+
+    pub fn read_word(&self, addr: u8, cmd: u8) -> Result<u16> {
+        let mut word = [0; 2];
+        self.read(addr, &[cmd], &mut word)?;
+        Ok(u16::from_le_bytes(word))
+    }
+
+    /// Process Call: write a word, and read one back.
+    pub fn process_call(&self, addr: u8, cmd: u8, word: u16) -> Result<u16> {
+        let [lo, hi] = word.to_le_bytes();
+        let mut result = [0; 2];
+        self.read(addr, &[cmd, lo, hi], &mut result)?;
+        Ok(u16::from_le_bytes(result))
+    }
+
+    /// Block Write, with the count byte generated from `data`, which must be
+    /// at most `BLOCK_MAX` long.
+    pub fn block_write(&self, addr: u8, cmd: u8, data: &[u8]) -> Result {
+        assert!(data.len() <= BLOCK_MAX, "SMBus block too long");
+        let mut buf = [0; BLOCK_MAX + 2];
+        buf[0] = cmd;
+        buf[1] = data.len() as u8;
+        buf[2 .. data.len() + 2].copy_from_slice(data);
+        self.write(addr, &buf[.. data.len() + 2])
+    }
+
+    /// Block Read, returning the count sent by the device.  If that exceeds
+    /// `data`, the excess is dropped.
+    pub fn block_read(&self, addr: u8, cmd: u8, data: &mut [u8])
+            -> Result<usize> {
+        let cmd = [cmd];
+        let mut count = 0u8;
+        let mut buf = [0; BLOCK_MAX + 1];
+        self.transaction(|| {
+            self.op(addr, false, cmd.as_ptr().addr(), 1, true,
+                    OpEnd::Restart)?;
+            // Read the count, holding the bus to carry on with the data.
+            let count_ptr = core::ptr::from_mut(&mut count).addr();
+            self.op(addr, true, count_ptr, 1, true, OpEnd::Continue)?;
+            let len = count as usize + self.pec as usize;
+            self.op(addr, true, buf.as_mut_ptr().addr(), len, false,
+                    OpEnd::Stop)
+        })?;
+        let count = count as usize;
+        let len = count.min(data.len());
+        data[.. len].copy_from_slice(&buf[.. len]);
+        Ok(count)
+    }
+
+    /// Find out who is asserting SMBALERT#, via the Alert Response Address.
+    /// Returns `None` if no device responds.  If several devices are
+    /// alerting, the lowest address wins, and the others keep SMBALERT#
+    /// asserted.
+    pub fn alert_response(&self) -> Result<Option<u8>> {
+        match self.receive_byte(ARA) {
+            Ok(addr) => Ok(Some(addr & !1)),
+            Err(I2cError::AddressNack) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Write `data`, followed by the PEC.
+    fn write(&self, addr: u8, data: &[u8]) -> Result {
+        self.transaction(
+            || self.op(addr, false, data.as_ptr().addr(), data.len(), true,
+                       OpEnd::Stop))
+    }
+
+    /// Write `cmd`, if not empty, and then read `data`, followed by the PEC.
+    /// At most 2 bytes are read.
+    fn read(&self, addr: u8, cmd: &[u8], data: &mut [u8]) -> Result {
+        let mut buf = [0; 3];
+        let len = data.len() + self.pec as usize;
+        self.transaction(|| {
+            if !cmd.is_empty() {
+                self.op(addr, false, cmd.as_ptr().addr(), cmd.len(), true,
+                        OpEnd::Restart)?;
+            }
+            self.op(addr, true, buf.as_mut_ptr().addr(), len, true,
+                    OpEnd::Stop)
+        })?;
+        data.copy_from_slice(&buf[.. data.len()]);
+        Ok(())
+    }
+
+    /// Run `f` with the context PEC handling set as requested.
+    fn transaction(&self, f: impl FnOnce() -> Result) -> Result {
+        self.context.set_pec(self.pec);
+        let result = f();
+        self.context.set_pec(false);
+        result
+    }
+
+    fn op(&self, addr: u8, read: bool, data: usize, len: usize, start: bool,
+          end: OpEnd) -> Result {
+        self.context.operation_start(
+            Address::Seven(addr), read, data, len, start, end);
+        self.context.wait()
+    }
+}
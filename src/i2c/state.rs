@@ -0,0 +1,581 @@
+//! The I2C controller transaction state machine.
+//!
+//! This is kept clear of the registers so that it can be tested on the host.
+//! `Machine::event()` takes an ISR register value, and returns the `Action`
+//! for `I2cContext::isr()` to carry out.  Every combination of state and event
+//! gives an action; anything that does not fit the transaction is reported as
+//! `I2cError::Unexpected`.
+
+use crate::vcell::VCell;
+
+use super::{I2cError, OpEnd};
+
+macro_rules!dbgln {($($tt:tt)*) => {if false {crate::dbgln!($($tt)*)}};}
+
+/// ISR register bits.  The corresponding ICR clear bits are at the same
+/// positions.
+pub const TXE    : u32 = 1 << 0;
+pub const TXIS   : u32 = 1 << 1;
+pub const RXNE   : u32 = 1 << 2;
+pub const NACKF  : u32 = 1 << 4;
+pub const STOPF  : u32 = 1 << 5;
+pub const TC     : u32 = 1 << 6;
+pub const TCR    : u32 = 1 << 7;
+pub const BERR   : u32 = 1 << 8;
+pub const ARLO   : u32 = 1 << 9;
+pub const OVR    : u32 = 1 << 10;
+pub const PECERR : u32 = 1 << 11;
+pub const TIMEOUT: u32 = 1 << 12;
+pub const ALERT  : u32 = 1 << 13;
+
+/// Events that abandon the transaction.
+const ERRORS: u32 = NACKF | BERR | ARLO | OVR | PECERR | TIMEOUT;
+/// Flags cleared by writing ICR.  TC and TCR are cleared by writing CR2.
+/// SMBALERT is not part of any transaction, so `Machine::event()` ignores
+/// it, but it is cleared along with the rest.
+pub const CLEAR: u32 = ERRORS | STOPF | ALERT;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[derive_const(Default)]
+pub enum State {
+    /// No transaction.
+    #[default]
+    Idle,
+    /// An operation with no data, just the address.
+    Address,
+    /// Write phase.
+    Write,
+    /// Write phase, to be followed by a repeated START and a read phase.
+    Turnaround,
+    /// Read phase.
+    Read,
+    /// The last data is programmed with AUTOEND, waiting for the STOP.
+    Stop,
+    /// The operation is finished, with the bus held for the next one.
+    Hold,
+    /// The transaction failed, waiting for `reset()`.
+    Error,
+}
+
+/// What the interrupt handler should do after an event.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Action {
+    /// Nothing, beyond clearing the flags.
+    None,
+    /// Program the next chunk of the current phase into CR2.
+    Reload{nbytes: u8, reload: bool},
+    /// Generate a repeated START for the read phase, keeping the address.
+    Restart{nbytes: u8, reload: bool},
+    /// The operation has finished with the bus held.  TC or TCR stays set
+    /// until the next operation writes CR2, so must be masked meanwhile.
+    Hold,
+    /// The transaction has finished with a STOP.
+    Done,
+    /// The transaction has failed.
+    Error(I2cError),
+}
+
+#[derive_const(Default)]
+pub struct Machine {
+    state: VCell<State>,
+    /// How the current operation finishes once its data is done.
+    end: VCell<OpEnd>,
+    /// Direction of the current phase.
+    read: VCell<bool>,
+    /// Number of bytes in the write phase.
+    tx_len: VCell<usize>,
+    /// Bytes in the current phase beyond those programmed into NBYTES.
+    reload_len: VCell<usize>,
+    /// Length of the read phase after a turnaround.
+    read_len: VCell<usize>,
+}
+
+impl Machine {
+    pub fn state(&self) -> State {self.state.read()}
+
+    /// Set up for a new operation, returning the NBYTES and RELOAD values for
+    /// CR2.  With a non-zero `then_read`, a write phase of `len` bytes is
+    /// followed by a repeated START and a read of `then_read` bytes; `end`
+    /// then applies to the read.
+    pub fn start(&self, read: bool, len: usize, end: OpEnd, then_read: usize)
+            -> (u8, bool) {
+        self.end.write(end);
+        self.read.write(read);
+        self.tx_len.write(if read {0} else {len});
+        self.read_len.write(then_read);
+        self.state.write(
+            if then_read != 0 {State::Turnaround}
+            else if len == 0 {State::Address}
+            else if read {State::Read}
+            else {State::Write});
+        self.chunk(len)
+    }
+
+    /// Whether CR2 needs AUTOEND for the operation started, i.e., it ends
+    /// with a STOP rather than a turnaround or holding the bus.  AUTOEND has
+    /// no effect while RELOAD is set.
+    pub fn autoend(&self) -> bool {
+        self.state.read() != State::Turnaround && self.end.read() == OpEnd::Stop
+    }
+
+    /// Return to idle, after the hardware has been reset.
+    pub fn reset(&self) {
+        self.state.write(State::Idle);
+        self.reload_len.write(0);
+        self.read_len.write(0);
+    }
+
+    /// Process the events in `isr`.  `tx_remaining` gives the number of bytes
+    /// of the write phase not yet taken by the DMA, and is only called for a
+    /// NACK.
+    pub fn event(&self, isr: u32, tx_remaining: impl FnOnce() -> usize)
+            -> Action {
+        let state = self.state.read();
+        if state == State::Idle || state == State::Error {
+            // Leftovers, e.g., the STOP after a NACK.  Nobody is waiting.
+            dbgln!("I2C ignore {isr:#x} in {state:?}");
+            return Action::None;
+        }
+        if isr & ERRORS != 0 {
+            let error =
+                if isr & NACKF != 0 {self.nack_error(isr, tx_remaining)}
+                else if isr & ARLO != 0 {I2cError::ArbitrationLost}
+                else if isr & BERR != 0 {I2cError::BusError}
+                else if isr & PECERR != 0 {I2cError::Pec}
+                else if isr & TIMEOUT != 0 {I2cError::Timeout}
+                else {I2cError::Overrun};
+            return self.fail(error);
+        }
+        if state == State::Hold {
+            // TC or TCR is still set, but masked.
+            return Action::None;
+        }
+
+        let reloading = self.reload_len.read() != 0;
+        let end = self.end.read();
+        if isr & TCR != 0 {
+            return match state {
+                State::Write | State::Read | State::Turnaround if reloading => {
+                    // Next chunk of a long transfer, the DMA just keeps going.
+                    let (nbytes, reload) = self.chunk(self.reload_len.read());
+                    Action::Reload{nbytes, reload}
+                },
+                State::Address | State::Write | State::Read
+                    if end == OpEnd::Continue => self.hold(),
+                _ => self.unexpected(state, isr),
+            };
+        }
+        if isr & TC != 0 {
+            return match state {
+                State::Turnaround if !reloading => {
+                    let len = self.read_len.read();
+                    dbgln!("I2C now read {len} bytes");
+                    self.read_len.write(0);
+                    self.read.write(true);
+                    self.state.write(State::Read);
+                    let (nbytes, reload) = self.chunk(len);
+                    Action::Restart{nbytes, reload}
+                },
+                State::Address | State::Write | State::Read
+                    if !reloading && end == OpEnd::Restart => self.hold(),
+                _ => self.unexpected(state, isr),
+            };
+        }
+        if isr & STOPF != 0 {
+            return match state {
+                State::Stop => self.done(),
+                State::Address if end == OpEnd::Stop => self.done(),
+                _ => self.unexpected(state, isr),
+            };
+        }
+        // Spurious, the flags must have been cleared already.
+        Action::None
+    }
+
+    /// Split off the first chunk of the current phase for NBYTES, leaving the
+    /// rest for reloads on TCR.  The last chunk has RELOAD set if the bus is
+    /// to be held for an operation continuing in the same direction, and
+    /// moves a phase ending in a STOP to `State::Stop`.
+    fn chunk(&self, len: usize) -> (u8, bool) {
+        let nbytes = len.min(255);
+        self.reload_len.write(len - nbytes);
+        let state = self.state.read();
+        let end = self.end.read();
+        let hold = end == OpEnd::Continue && state != State::Turnaround;
+        if len <= 255 && end == OpEnd::Stop
+            && (state == State::Write || state == State::Read) {
+            self.state.write(State::Stop);
+        }
+        (nbytes as u8, len > 255 || hold)
+    }
+
+    fn hold(&self) -> Action {
+        dbgln!("I2C hold");
+        self.state.write(State::Hold);
+        Action::Hold
+    }
+
+    fn done(&self) -> Action {
+        dbgln!("I2C STOP");
+        self.state.write(State::Idle);
+        Action::Done
+    }
+
+    fn fail(&self, error: I2cError) -> Action {
+        dbgln!("I2C error {error:?}");
+        self.state.write(State::Error);
+        Action::Error(error)
+    }
+
+    fn unexpected(&self, state: State, isr: u32) -> Action {
+        dbgln!("I2C unexpected {isr:#x} in {state:?}");
+        self.fail(I2cError::Unexpected)
+    }
+
+    /// Work out whether a NACK was for the address or for a data byte.
+    fn nack_error(&self, isr: u32, tx_remaining: impl FnOnce() -> usize)
+            -> I2cError {
+        if self.read.read() {
+            // Controller receivers don't get NACKs for data.
+            return I2cError::AddressNack;
+        }
+        // Bytes that have left TXDR for the shift register.
+        let sent = self.tx_len.read().saturating_sub(tx_remaining())
+            .saturating_sub((isr & TXE == 0) as usize);
+        if sent == 0 {
+            I2cError::AddressNack
+        }
+        else {
+            I2cError::DataNack(sent - 1)
+        }
+    }
+}
+
+/// Simulated ISR and CR2, updated by the actions as the hardware would be.
+#[cfg(test)]
+struct Sim {
+    machine: Machine,
+    isr: u32,
+    nbytes: u8,
+    reload: bool,
+    autoend: bool,
+    read: bool,
+    tcie: bool,
+    /// Bytes not yet taken by the TX DMA.
+    tx_remaining: usize,
+    error: Option<I2cError>,
+    done: bool,
+}
+
+#[cfg(test)]
+impl Sim {
+    fn start(read: bool, len: usize, end: OpEnd, then_read: usize) -> Sim {
+        let machine = Machine::default();
+        let (nbytes, reload) = machine.start(read, len, end, then_read);
+        let autoend = machine.autoend();
+        Sim{machine, isr: TXE, nbytes, reload, autoend, read, tcie: true,
+            tx_remaining: if read {0} else {len},
+            error: None, done: false}
+    }
+
+    /// Run the ISR with `flags` raised, as `I2cContext::isr()` does.  TC and
+    /// TCR only interrupt while TCIE is set.
+    fn event(&mut self, flags: u32) -> Action {
+        self.isr |= flags;
+        let mut pending = self.isr;
+        if !self.tcie {
+            pending &= !(TC | TCR);
+        }
+        let tx_remaining = self.tx_remaining;
+        let action = self.machine.event(pending, || tx_remaining);
+        self.isr &= !CLEAR;
+        match action {
+            Action::None => (),
+            Action::Reload{nbytes, reload} => {
+                self.isr &= !TCR;
+                self.nbytes = nbytes;
+                self.reload = reload;
+            },
+            Action::Restart{nbytes, reload} => {
+                self.isr &= !TC;
+                self.nbytes = nbytes;
+                self.reload = reload;
+                self.read = true;
+            },
+            Action::Hold => {
+                self.tcie = false;
+                self.done = true;
+            },
+            Action::Done => self.done = true,
+            Action::Error(e) => {
+                self.error.get_or_insert(e);
+                self.done = true;
+            },
+        }
+        action
+    }
+
+    /// Start the next operation after a `Hold`, in the order of
+    /// `operation_start()`: the state machine first, then the CR2 write that
+    /// clears TC and TCR, and only then TCIE.
+    fn next(&mut self, read: bool, len: usize, end: OpEnd) {
+        let (nbytes, reload) = self.machine.start(read, len, end, 0);
+        // Another interrupt in between must not see the old TC or TCR.
+        assert_eq!(self.event(0), Action::None);
+        self.isr &= !(TC | TCR);
+        (self.nbytes, self.reload) = (nbytes, reload);
+        self.autoend = self.machine.autoend();
+        self.read = read;
+        self.tx_remaining = if read {0} else {len};
+        self.tcie = true;
+        self.done = false;
+    }
+
+    /// The DMA moves `n` bytes, and NBYTES counts down.
+    fn transfer(&mut self, n: usize) {
+        if !self.read {
+            self.tx_remaining -= n;
+        }
+        self.nbytes -= n as u8;
+    }
+}
+
+#[test]
+fn test_write_stop() {
+    let mut sim = Sim::start(false, 4, OpEnd::Stop, 0);
+    assert_eq!((sim.nbytes, sim.reload), (4, false));
+    assert_eq!(sim.machine.state(), State::Stop);
+    sim.transfer(4);
+    assert_eq!(sim.event(STOPF), Action::Done);
+    assert_eq!(sim.machine.state(), State::Idle);
+    assert_eq!(sim.error, None);
+}
+
+#[test]
+fn test_long_read_reloads() {
+    let mut sim = Sim::start(true, 600, OpEnd::Stop, 0);
+    assert_eq!((sim.nbytes, sim.reload), (255, true));
+    assert_eq!(sim.machine.state(), State::Read);
+    sim.transfer(255);
+    assert_eq!(sim.event(TCR), Action::Reload{nbytes: 255, reload: true});
+    sim.transfer(255);
+    assert_eq!(sim.event(TCR), Action::Reload{nbytes: 90, reload: false});
+    assert_eq!(sim.machine.state(), State::Stop);
+    sim.transfer(90);
+    assert_eq!(sim.event(STOPF), Action::Done);
+    assert!(sim.done);
+}
+
+#[test]
+fn test_write_read_turnaround() {
+    let mut sim = Sim::start(false, 2, OpEnd::Stop, 3);
+    assert_eq!(sim.machine.state(), State::Turnaround);
+    assert_eq!((sim.nbytes, sim.reload), (2, false));
+    sim.transfer(2);
+    assert_eq!(sim.event(TC), Action::Restart{nbytes: 3, reload: false});
+    assert!(sim.read);
+    assert_eq!(sim.machine.state(), State::Stop);
+    assert!(!sim.done);
+    sim.transfer(3);
+    assert_eq!(sim.event(STOPF), Action::Done);
+    assert_eq!(sim.error, None);
+}
+
+#[test]
+fn test_write_then_empty_read() {
+    // With nothing to read, there is no turnaround, so the write must have
+    // AUTOEND, or the TC would come in `State::Stop`.
+    let mut sim = Sim::start(false, 1, OpEnd::Stop, 0);
+    assert_eq!(sim.machine.state(), State::Stop);
+    assert!(sim.autoend);
+    sim.transfer(1);
+    assert_eq!(sim.event(STOPF), Action::Done);
+    assert_eq!(sim.error, None);
+    // A turnaround must not have it.
+    let sim = Sim::start(false, 1, OpEnd::Stop, 2);
+    assert!(!sim.autoend);
+}
+
+#[test]
+fn test_hold_then_read() {
+    // A write holding the bus, then a read after a repeated START.
+    let mut sim = Sim::start(false, 2, OpEnd::Restart, 0);
+    assert!(!sim.autoend);
+    sim.transfer(2);
+    assert_eq!(sim.event(TC), Action::Hold);
+    assert!(!sim.tcie);
+    assert_eq!(sim.event(0), Action::None);
+    sim.next(true, 3, OpEnd::Stop);
+    assert_eq!(sim.machine.state(), State::Stop);
+    assert!(sim.autoend);
+    sim.transfer(3);
+    assert_eq!(sim.event(STOPF), Action::Done);
+    assert_eq!(sim.error, None);
+}
+
+#[test]
+fn test_hold_then_continue() {
+    // A write continued without a repeated START.
+    let mut sim = Sim::start(false, 1, OpEnd::Continue, 0);
+    assert_eq!((sim.nbytes, sim.reload), (1, true));
+    sim.transfer(1);
+    assert_eq!(sim.event(TCR), Action::Hold);
+    sim.next(false, 4, OpEnd::Stop);
+    assert_eq!((sim.nbytes, sim.reload), (4, false));
+    sim.transfer(4);
+    assert_eq!(sim.event(STOPF), Action::Done);
+    assert_eq!(sim.error, None);
+}
+
+#[test]
+fn test_smbus_read_word() {
+    // `SmBus::read_word()` with PEC: the command, then a repeated START and
+    // the word and PEC.
+    let mut sim = Sim::start(false, 1, OpEnd::Restart, 0);
+    sim.transfer(1);
+    assert_eq!(sim.event(TC), Action::Hold);
+    sim.next(true, 3, OpEnd::Stop);
+    sim.transfer(3);
+    assert_eq!(sim.event(STOPF), Action::Done);
+    assert_eq!(sim.error, None);
+}
+
+#[test]
+fn test_smbus_block_read() {
+    // `SmBus::block_read()`: the command, the count with the bus held, and
+    // then the data carrying straight on.
+    let mut sim = Sim::start(false, 1, OpEnd::Restart, 0);
+    sim.transfer(1);
+    assert_eq!(sim.event(TC), Action::Hold);
+    sim.next(true, 1, OpEnd::Continue);
+    assert_eq!((sim.nbytes, sim.reload), (1, true));
+    sim.transfer(1);
+    assert_eq!(sim.event(TCR), Action::Hold);
+    sim.next(true, 5, OpEnd::Stop);
+    assert_eq!((sim.nbytes, sim.reload), (5, false));
+    sim.transfer(5);
+    assert_eq!(sim.event(STOPF), Action::Done);
+    assert_eq!(sim.error, None);
+}
+
+#[test]
+fn test_long_write_turnaround() {
+    let mut sim = Sim::start(false, 300, OpEnd::Stop, 1);
+    assert_eq!((sim.nbytes, sim.reload), (255, true));
+    sim.transfer(255);
+    assert_eq!(sim.event(TCR), Action::Reload{nbytes: 45, reload: false});
+    assert_eq!(sim.machine.state(), State::Turnaround);
+    sim.transfer(45);
+    assert_eq!(sim.event(TC), Action::Restart{nbytes: 1, reload: false});
+}
+
+#[test]
+fn test_stopf_during_turnaround() {
+    // This used to hang, waiting for a TC that never comes.
+    let mut sim = Sim::start(false, 2, OpEnd::Stop, 3);
+    sim.transfer(2);
+    assert_eq!(sim.event(STOPF), Action::Error(I2cError::Unexpected));
+    assert!(sim.done);
+    assert_eq!(sim.machine.state(), State::Error);
+    // Further events are ignored until reset.
+    assert_eq!(sim.event(TC), Action::None);
+    sim.machine.reset();
+    assert_eq!(sim.machine.state(), State::Idle);
+}
+
+#[test]
+fn test_unexpected_events() {
+    // A TC in a transfer ending with AUTOEND.
+    let mut sim = Sim::start(false, 4, OpEnd::Stop, 0);
+    assert_eq!(sim.event(TC), Action::Error(I2cError::Unexpected));
+    // A TCR with nothing left to reload.
+    let mut sim = Sim::start(true, 4, OpEnd::Restart, 0);
+    assert_eq!(sim.event(TCR), Action::Error(I2cError::Unexpected));
+    // A STOP before the last chunk.
+    let mut sim = Sim::start(true, 300, OpEnd::Stop, 0);
+    assert_eq!(sim.event(STOPF), Action::Error(I2cError::Unexpected));
+}
+
+#[test]
+fn test_spurious() {
+    let mut sim = Sim::start(false, 4, OpEnd::Stop, 0);
+    assert_eq!(sim.event(0), Action::None);
+    assert_eq!(sim.machine.state(), State::Stop);
+    let mut sim = Sim::start(false, 0, OpEnd::Stop, 0);
+    sim.machine.reset();
+    assert_eq!(sim.event(STOPF | NACKF), Action::None);
+}
+
+#[test]
+fn test_address_nack() {
+    let mut sim = Sim::start(false, 0, OpEnd::Stop, 0);
+    assert_eq!(sim.machine.state(), State::Address);
+    assert_eq!(sim.event(NACKF), Action::Error(I2cError::AddressNack));
+    // The STOP generated after the NACK is ignored.
+    assert_eq!(sim.event(STOPF), Action::None);
+    assert_eq!(sim.error, Some(I2cError::AddressNack));
+
+    let mut sim = Sim::start(false, 4, OpEnd::Stop, 0);
+    // First byte is in TXDR, not yet sent.
+    sim.transfer(1);
+    sim.isr &= !TXE;
+    assert_eq!(sim.event(NACKF), Action::Error(I2cError::AddressNack));
+
+    let mut sim = Sim::start(true, 4, OpEnd::Stop, 0);
+    assert_eq!(sim.event(NACKF), Action::Error(I2cError::AddressNack));
+}
+
+#[test]
+fn test_data_nack() {
+    let mut sim = Sim::start(false, 4, OpEnd::Stop, 0);
+    // Three bytes taken by the DMA, the third still in TXDR.
+    sim.transfer(3);
+    sim.isr &= !TXE;
+    assert_eq!(sim.event(NACKF), Action::Error(I2cError::DataNack(1)));
+}
+
+#[test]
+fn test_hold() {
+    let mut sim = Sim::start(false, 2, OpEnd::Restart, 0);
+    assert_eq!(sim.machine.state(), State::Write);
+    sim.transfer(2);
+    assert_eq!(sim.event(TC), Action::Hold);
+    assert!(!sim.tcie);
+    // TC stays set while held, and other events don't trip over it.
+    assert_eq!(sim.event(0), Action::None);
+
+    let mut sim = Sim::start(true, 256, OpEnd::Continue, 0);
+    assert_eq!((sim.nbytes, sim.reload), (255, true));
+    sim.transfer(255);
+    assert_eq!(sim.event(TCR), Action::Reload{nbytes: 1, reload: true});
+    sim.transfer(1);
+    assert_eq!(sim.event(TCR), Action::Hold);
+    assert_eq!(sim.machine.state(), State::Hold);
+    // An error while held is still reported.
+    assert_eq!(sim.event(BERR), Action::Error(I2cError::BusError));
+}
+
+#[test]
+fn test_bus_errors() {
+    let mut sim = Sim::start(false, 4, OpEnd::Stop, 0);
+    assert_eq!(sim.event(ARLO | BERR),
+               Action::Error(I2cError::ArbitrationLost));
+    let mut sim = Sim::start(true, 4, OpEnd::Stop, 0);
+    assert_eq!(sim.event(OVR), Action::Error(I2cError::Overrun));
+    let mut sim = Sim::start(true, 4, OpEnd::Stop, 0);
+    assert_eq!(sim.event(TIMEOUT), Action::Error(I2cError::Timeout));
+    // A bad PEC is NACKed, followed by a STOP.
+    let mut sim = Sim::start(true, 3, OpEnd::Stop, 0);
+    sim.transfer(3);
+    assert_eq!(sim.event(PECERR), Action::Error(I2cError::Pec));
+    assert_eq!(sim.event(NACKF | STOPF), Action::None);
+}
+
+#[test]
+fn test_alert_ignored() {
+    let mut sim = Sim::start(false, 4, OpEnd::Stop, 0);
+    assert_eq!(sim.event(ALERT), Action::None);
+    assert_eq!(sim.isr & ALERT, 0);
+    sim.transfer(4);
+    assert_eq!(sim.event(STOPF), Action::Done);
+}
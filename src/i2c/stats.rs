@@ -0,0 +1,168 @@
+//! I2C event counters, and a trace of recent operations.  The trace is only
+//! kept with the `i2c_trace` feature.
+
+use core::fmt::Write;
+
+use crate::vcell::VCell;
+
+use super::{Address, I2cError, Result};
+
+/// Number of operations kept in the trace.
+pub const TRACE_LEN: usize = if cfg!(feature = "i2c_trace") {16} else {0};
+
+/// Event counts, see `I2cContext::stats()`.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Stats {
+    /// Transactions started.  The operations continuing a transaction that
+    /// holds the bus, and retries, are not counted again.
+    pub transactions: u32,
+    pub nacks: u32,
+    pub arbitration_lost: u32,
+    /// Transactions restarted after losing arbitration.
+    pub retries: u32,
+    pub bus_errors: u32,
+    /// Bus recoveries that had to clock out a stuck target.
+    pub recoveries: u32,
+    /// Hardware and deadline timeouts.
+    pub timeouts: u32,
+    /// Overrun, DMA, PEC and unexpected event errors.
+    pub other_errors: u32,
+}
+
+#[derive_const(Default)]
+pub struct Counters {
+    transactions: VCell<u32>,
+    nacks: VCell<u32>,
+    arbitration_lost: VCell<u32>,
+    retries: VCell<u32>,
+    bus_errors: VCell<u32>,
+    recoveries: VCell<u32>,
+    timeouts: VCell<u32>,
+    other_errors: VCell<u32>,
+    /// Address NACKs are expected, and not counted, see `ack_poll()`.
+    quiet: VCell<bool>,
+}
+
+fn bump(counter: &VCell<u32>) {counter.write(counter.read().wrapping_add(1))}
+
+impl Counters {
+    pub fn start(&self) {bump(&self.transactions)}
+    pub fn recovery(&self) {bump(&self.recoveries)}
+    pub fn retry(&self) {bump(&self.retries)}
+    pub fn quiet(&self, quiet: bool) {self.quiet.write(quiet)}
+
+    pub fn error(&self, error: I2cError) {
+        if error == I2cError::AddressNack && self.quiet.read() {
+            return;
+        }
+        bump(match error {
+            I2cError::AddressNack | I2cError::DataNack(_) => &self.nacks,
+            I2cError::ArbitrationLost => &self.arbitration_lost,
+            I2cError::BusError => &self.bus_errors,
+            I2cError::Timeout => &self.timeouts,
+            _ => &self.other_errors,
+        });
+    }
+
+    pub fn get(&self) -> Stats {
+        Stats{
+            transactions: self.transactions.read(),
+            nacks: self.nacks.read(),
+            arbitration_lost: self.arbitration_lost.read(),
+            retries: self.retries.read(),
+            bus_errors: self.bus_errors.read(),
+            recoveries: self.recoveries.read(),
+            timeouts: self.timeouts.read(),
+            other_errors: self.other_errors.read(),
+        }
+    }
+}
+
+/// One traced operation.
+#[derive(Clone, Copy, Debug)]
+pub struct TraceEntry {
+    pub addr: Address,
+    /// Bytes written, including any register address.
+    pub wlen: usize,
+    /// Bytes read.
+    pub rlen: usize,
+    /// All the ISR flags seen, ORed together.
+    pub isr: u32,
+    /// `None` while in progress.
+    pub result: Option<Result>,
+}
+
+impl TraceEntry {
+    const EMPTY: TraceEntry = TraceEntry{
+        addr: Address::Seven(0), wlen: 0, rlen: 0, isr: 0, result: None};
+}
+
+pub struct Trace {
+    entries: [VCell<TraceEntry>; TRACE_LEN],
+    /// Number of operations ever started.
+    count: VCell<usize>,
+}
+
+impl const Default for Trace {
+    fn default() -> Self {
+        Trace{entries: [const {VCell::new(TraceEntry::EMPTY)}; TRACE_LEN],
+              count: VCell::new(0)}
+    }
+}
+
+impl Trace {
+    pub fn start(&self, addr: Address, wlen: usize, rlen: usize) {
+        if TRACE_LEN == 0 {
+            return;
+        }
+        let count = self.count.read();
+        self.entries[slot(count)].write(
+            TraceEntry{addr, wlen, rlen, isr: 0, result: None});
+        self.count.write(count.wrapping_add(1));
+    }
+
+    pub fn isr(&self, status: u32) {
+        self.update(|e| e.isr |= status);
+    }
+
+    pub fn finish(&self, result: Result) {
+        self.update(|e| e.result = Some(result));
+    }
+
+    fn update(&self, f: impl FnOnce(&mut TraceEntry)) {
+        let count = self.count.read();
+        if TRACE_LEN == 0 || count == 0 {
+            return;
+        }
+        let entry = &self.entries[slot(count - 1)];
+        let mut e = entry.read();
+        f(&mut e);
+        entry.write(e);
+    }
+
+    /// The traced operations, oldest first.
+    pub fn entries(&self) -> impl Iterator<Item = TraceEntry> + '_ {
+        let count = self.count.read();
+        let first = count.saturating_sub(TRACE_LEN);
+        (first .. count).map(|i| self.entries[slot(i)].read())
+    }
+}
+
+/// Index into the trace.  Without the trace, this is never used, but must
+/// still compile.
+fn slot(i: usize) -> usize {i % TRACE_LEN.max(1)}
+
+/// Write the counters and trace, e.g., to a `debug::Marker`.
+pub fn dump(w: &mut impl Write, stats: &Stats, trace: &Trace)
+        -> core::fmt::Result {
+    writeln!(w, "I2C transactions {} NACK {} ARLO {} retries {} BERR {} \
+                 recoveries {} timeouts {} other {}",
+             stats.transactions, stats.nacks, stats.arbitration_lost,
+             stats.retries, stats.bus_errors, stats.recoveries, stats.timeouts,
+             stats.other_errors)?;
+    for e in trace.entries() {
+        writeln!(w, "  {:?} W {} R {} ISR {:#x} {:?}",
+                 e.addr, e.wlen, e.rlen, e.isr, e.result)?;
+    }
+    Ok(())
+}
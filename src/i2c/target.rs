@@ -0,0 +1,234 @@
+//! I2C target (slave) mode.  This reuses the pin and DMA wiring of
+//! `i2c::Meta`; the target specific parts are in `TargetMeta`.
+
+use crate::dma::DMA_Channel;
+use crate::utils::barrier;
+
+use super::{Address, F_DMA_RX, F_DMA_TX, I2cError, Meta, Timing};
+use super::wakeup::hsi_kernel_clock;
+
+macro_rules!dbgln {($($tt:tt)*) => {if false {crate::dbgln!($($tt)*)}};}
+
+/// Own address configuration.  7-bit addresses are in the 8-bit form used
+/// elsewhere in this crate, i.e., shifted left by one.
+#[derive(Clone, Copy)]
+#[derive_const(Default)]
+pub struct OwnAddress {
+    /// Primary address, OAR1.
+    pub oa1: Option<Address> = None,
+    /// Secondary 7-bit address, OAR2.
+    pub oa2: Option<u8> = None,
+    /// Number of low address bits ignored when matching OAR2, 0 to 7.
+    pub oa2_mask: u8 = 0,
+}
+
+pub trait TargetMeta: Meta {
+    fn own_address(&self) -> OwnAddress;
+
+    /// The controller has addressed us for writing.  Return the address and
+    /// length of the buffer to receive into.  The buffer must remain valid
+    /// until `write_complete()`.  Bytes beyond the buffer are discarded.
+    fn write_requested(&mut self, addr: u8) -> (usize, usize);
+    /// A write from the controller has finished, with `len` bytes received.
+    fn write_complete(&mut self, addr: u8, len: usize);
+
+    /// The controller has addressed us for reading.  Return the address and
+    /// length of the data to send.  The data must remain valid until
+    /// `read_complete()`.  If the controller reads further, it gets 0xff.
+    fn read_requested(&mut self, addr: u8) -> (usize, usize);
+    /// A read from the controller has finished, with `len` bytes from our
+    /// buffer sent.
+    fn read_complete(&mut self, _addr: u8, _len: usize) {}
+
+    /// A bus error occurred.  Any transfer in progress is abandoned.
+    fn target_error(&mut self, _error: I2cError) {}
+
+    /// Wake the MCU from Stop mode on an address match, see
+    /// `I2cTarget::stop()`.  This switches the I2C kernel clock to the HSI.
+    fn wakeup(&self) -> bool {false}
+}
+
+/// What the controller is doing to us.
+#[derive(Clone, Copy, PartialEq, Eq)]
+#[derive_const(Default)]
+enum Active {
+    #[default]
+    Idle,
+    Write(u8),
+    Read(u8),
+}
+
+#[derive_const(Default)]
+pub struct I2cTarget<M> {
+    active: Active,
+    /// Length of the DMA buffer for the active transfer.
+    len: usize,
+    pub meta: M,
+}
+
+impl<M: TargetMeta> I2cTarget<M> {
+    pub fn isr(&mut self) {
+        let i2c = self.meta.i2c();
+        let status = i2c.ISR.read();
+        dbgln!("I2C target ISR {:#x}", status.bits());
+
+        if status.BERR().bit() || status.ARLO().bit() || status.OVR().bit() {
+            let error = if status.BERR().bit() {I2cError::BusError}
+                else if status.ARLO().bit() {I2cError::ArbitrationLost}
+                else {I2cError::Overrun};
+            i2c.ICR.write(
+                |w| w.BERRCF().set_bit().ARLOCF().set_bit().OVRCF().set_bit());
+            self.meta.tx_channel().abort();
+            self.meta.rx_channel().abort();
+            self.active = Active::Idle;
+            self.meta.target_error(error);
+        }
+        if status.NACKF().bit() {
+            // The controller NACKs the last byte it reads, which is normal.
+            i2c.ICR.write(|w| w.NACKCF().set_bit());
+        }
+        if status.RXNE().bit() && i2c.CR1.read().RXIE().bit() {
+            // Overflow past the end of the buffer.
+            i2c.RXDR.read();
+        }
+        if status.TXIS().bit() && i2c.CR1.read().TXIE().bit() {
+            // Underflow past the end of the data.
+            i2c.TXDR.write(|w| w.TXDATA().bits(0xff));
+        }
+        if status.ADDR().bit() {
+            // A repeated start finishes the previous transfer.
+            self.complete();
+            let addr = status.ADDCODE().bits() << 1;
+            self.start(addr, status.DIR().bit());
+            // Clearing ADDR releases the clock stretch.
+            i2c.ICR.write(|w| w.ADDRCF().set_bit());
+        }
+        if status.STOPF().bit() {
+            i2c.ICR.write(|w| w.STOPCF().set_bit());
+            self.complete();
+        }
+        // Stop the ISR from prematurely retriggering, as for the controller.
+        i2c.ISR.read();
+    }
+
+    /// Call from the DMA channel interrupt handlers, with `flag` being
+    /// `F_DMA_RX` or `F_DMA_TX`, once the channel has finished.  If the
+    /// controller keeps going, we switch to discarding or padding bytes.
+    pub fn dma_isr(&mut self, flag: u8) {
+        let i2c = self.meta.i2c();
+        if flag & F_DMA_RX != 0 && matches!(self.active, Active::Write(_)) {
+            i2c.CR1.modify(|_,w| w.RXDMAEN().clear_bit().RXIE().set_bit());
+        }
+        if flag & F_DMA_TX != 0 && matches!(self.active, Active::Read(_)) {
+            i2c.CR1.modify(|_,w| w.TXDMAEN().clear_bit().TXIE().set_bit());
+        }
+    }
+
+    /// No transfer is in progress.
+    pub fn idle(&self) -> bool {self.active == Active::Idle}
+
+    fn start(&mut self, addr: u8, read: bool) {
+        let i2c = self.meta.i2c();
+        i2c.CR1.modify(
+            |_,w| w.RXDMAEN().set_bit().TXDMAEN().set_bit()
+                .RXIE().clear_bit().TXIE().clear_bit());
+        if read {
+            dbgln!("I2C target read {addr:#x}");
+            // Flush anything left in TXDR from last time.
+            i2c.ISR.write(|w| w.TXE().set_bit());
+            let (data, len) = self.meta.read_requested(addr);
+            self.active = Active::Read(addr);
+            self.len = len;
+            barrier();
+            if len != 0 {
+                self.meta.tx_channel().write(data, len, 0);
+            }
+            else {
+                // A zero length DMA would never finish, so pad from the start.
+                i2c.CR1.modify(|_,w| w.TXDMAEN().clear_bit().TXIE().set_bit());
+            }
+        }
+        else {
+            dbgln!("I2C target write {addr:#x}");
+            let (data, len) = self.meta.write_requested(addr);
+            self.active = Active::Write(addr);
+            self.len = len;
+            barrier();
+            if len != 0 {
+                self.meta.rx_channel().read(data, len, 0);
+            }
+            else {
+                // Likewise, discard from the start.
+                i2c.CR1.modify(|_,w| w.RXDMAEN().clear_bit().RXIE().set_bit());
+            }
+        }
+    }
+
+    fn complete(&mut self) {
+        match self.active {
+            Active::Idle => (),
+            Active::Write(addr) => {
+                // No DMA was started for an empty buffer.
+                let done = self.len.saturating_sub(
+                    self.meta.rx_channel().remaining());
+                self.meta.rx_channel().abort();
+                barrier();
+                dbgln!("I2C target write done {done}");
+                self.meta.write_complete(addr, done);
+            },
+            Active::Read(addr) => {
+                let done = self.len.saturating_sub(
+                    self.meta.tx_channel().remaining());
+                self.meta.tx_channel().abort();
+                // Any byte left in TXDR was never sent.
+                let done = done.saturating_sub(
+                    !self.meta.i2c().ISR.read().TXE().bit() as usize);
+                self.meta.i2c().ISR.write(|w| w.TXE().set_bit());
+                dbgln!("I2C target read done {done}");
+                self.meta.read_complete(addr, done);
+            },
+        }
+        self.active = Active::Idle;
+    }
+
+    /// Set up the I2C and DMA.  In target mode only the SCLDEL and SDADEL
+    /// fields of the timing matter.  With `TargetMeta::wakeup()`, the timing
+    /// is for the HSI.
+    pub fn initialize(&self, timing: Timing) {
+        let i2c = self.meta.i2c();
+        let wakeup = self.meta.wakeup();
+        i2c.CR1.write(|w| w.PE().clear_bit());
+        if wakeup {
+            hsi_kernel_clock(i2c);
+        }
+        i2c.TIMINGR.write(|w| w.bits(timing.timingr()));
+        self.meta.rx_channel().read_from(
+            i2c.RXDR.as_ptr() as *const u8, self.meta.rx_muxin());
+        self.meta.tx_channel().writes_to(
+            i2c.TXDR.as_ptr() as *mut u8, self.meta.tx_muxin());
+
+        let own = self.meta.own_address();
+        // The own addresses are only writable while not enabled.
+        i2c.OAR1.write(|w| w);
+        i2c.OAR2.write(|w| w);
+        match own.oa1 {
+            None => (),
+            Some(Address::Seven(oa1)) => i2c.OAR1.write(
+                |w| w.OA1().bits(oa1 as u16).OA1EN().set_bit()),
+            Some(Address::Ten(oa1)) => i2c.OAR1.write(
+                |w| w.OA1().bits(oa1).OA1MODE().set_bit().OA1EN().set_bit()),
+        }
+        if let Some(oa2) = own.oa2 {
+            i2c.OAR2.write(
+                |w| w.OA2().bits(oa2 >> 1).OA2MSK().bits(own.oa2_mask)
+                    .OA2EN().set_bit());
+        }
+        // Wake-up needs the analog filter on, and the digital filter off,
+        // which this leaves them.
+        i2c.CR1.write(
+            |w|w.TXDMAEN().set_bit().RXDMAEN().set_bit().PE().set_bit()
+                .ADDRIE().set_bit().NACKIE().set_bit().ERRIE().set_bit()
+                .STOPIE().set_bit().WUPEN().bit(wakeup));
+        barrier();
+    }
+}
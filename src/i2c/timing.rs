@@ -0,0 +1,221 @@
+//! Compile time calculation of the I2C TIMINGR register, following the timing
+//! rules in the reference manual.  The analog filter is assumed on, and the
+//! digital filter off.
+
+/// Bus speed grades.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Speed {
+    /// Standard-mode, 100kHz.
+    Standard,
+    /// Fast-mode, 400kHz.
+    Fast,
+    /// Fast-mode Plus, 1MHz.
+    FastPlus,
+}
+
+/// Limits from the I2C specification, all times in ns.
+struct Limits {
+    freq: u32,
+    low_min: u32,
+    high_min: u32,
+    su_dat_min: u32,
+    /// Fast-mode Plus has no maximum data hold time.
+    hd_dat_max: Option<u32>,
+    rise_max: u32,
+    fall_max: u32,
+}
+
+impl Speed {
+    const fn limits(self) -> Limits {
+        match self {
+            Speed::Standard => Limits{
+                freq: 100_000, low_min: 4700, high_min: 4000,
+                su_dat_min: 250, hd_dat_max: Some(3450),
+                rise_max: 1000, fall_max: 300},
+            Speed::Fast => Limits{
+                freq: 400_000, low_min: 1300, high_min: 600,
+                su_dat_min: 100, hd_dat_max: Some(900),
+                rise_max: 300, fall_max: 300},
+            Speed::FastPlus => Limits{
+                freq: 1_000_000, low_min: 500, high_min: 260,
+                su_dat_min: 50, hd_dat_max: None,
+                rise_max: 120, fall_max: 120},
+        }
+    }
+}
+
+/// Analog filter delay range, ns.
+const AF_MIN: u32 = 50;
+const AF_MAX: u32 = 260;
+
+/// The fields of TIMINGR, and the speed they were calculated for.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Timing {
+    pub speed: Speed,
+    pub presc: u8,
+    pub scldel: u8,
+    pub sdadel: u8,
+    pub sclh: u8,
+    pub scll: u8,
+}
+
+impl Timing {
+    /// Calculate the timing for a kernel clock of `clk` Hz and the given bus
+    /// speed and rise and fall times (ns).  The lowest prescaler that meets
+    /// the specification is used.  Panics if the speed cannot be met, i.e.,
+    /// the specification cannot be satisfied or the bus would run at less than
+    /// 3/4 of the nominal rate.  Evaluating this in a const turns that into a
+    /// build failure.
+    pub const fn new(clk: u32, speed: Speed, rise: u32, fall: u32) -> Timing {
+        let l = speed.limits();
+        assert!(rise <= l.rise_max, "I2C rise time too slow");
+        assert!(fall <= l.fall_max, "I2C fall time too slow");
+
+        // Everything is worked in kernel clock cycles.  Minimums round up,
+        // maximums round down.
+        let sdadel_min = cycles_up(clk, fall.saturating_sub(AF_MIN))
+            .saturating_sub(3);
+        let sdadel_max = match l.hd_dat_max {
+            Some(hd_dat_max) =>
+                cycles_down(clk, hd_dat_max - rise - AF_MAX) as i64 - 4,
+            None => i64::MAX,
+        };
+        let scldel_min = cycles_up(clk, rise + l.su_dat_min);
+        // The SCL synchronization delays add to the programmed low and high
+        // times.  Take the minimum, so that we never exceed the bus speed.
+        let sync = cycles_down(clk, rise + fall + 2 * AF_MIN) + 4;
+        let nominal = (clk as u64).div_ceil(l.freq as u64);
+        let period = nominal.saturating_sub(sync);
+        // The low and high times on the bus are also stretched by at least
+        // the analog filter delay and two kernel clocks.  Each is at least
+        // one cycle.
+        let low_min = cycles_up(clk, l.low_min - AF_MIN)
+            .saturating_sub(3) + 1;
+        let high_min = cycles_up(clk, l.high_min - AF_MIN)
+            .saturating_sub(3) + 1;
+
+        let mut presc = 0;
+        while presc < 16 {
+            let div = presc + 1;
+            let sdadel = sdadel_min.div_ceil(div);
+            let scldel = scldel_min.div_ceil(div).saturating_sub(1);
+            let mut low = low_min.div_ceil(div);
+            let mut high = high_min.div_ceil(div);
+            let total = period.div_ceil(div);
+            if low + high < total {
+                let extra = total - low - high;
+                high += extra / 2;
+                low += extra - extra / 2;
+            }
+            if 3 * ((low + high) * div + sync) > 4 * nominal {
+                panic!("I2C kernel clock too slow for bus speed");
+            }
+            if sdadel <= 15 && (sdadel * div) as i64 <= sdadel_max
+                && scldel <= 15 && low <= 256 && high <= 256 {
+                return Timing{
+                    speed, presc: presc as u8, scldel: scldel as u8,
+                    sdadel: sdadel as u8,
+                    sclh: (high - 1) as u8, scll: (low - 1) as u8};
+            }
+            presc += 1;
+        }
+        panic!("I2C timing cannot be met");
+    }
+
+    /// The TIMINGR register value.
+    pub const fn timingr(&self) -> u32 {
+        (self.presc as u32) << 28 | (self.scldel as u32) << 20
+            | (self.sdadel as u32) << 16 | (self.sclh as u32) << 8
+            | self.scll as u32
+    }
+}
+
+/// Kernel clock cycles in `ns`, rounded up.
+const fn cycles_up(clk: u32, ns: u32) -> u64 {
+    (ns as u64 * clk as u64).div_ceil(1_000_000_000)
+}
+
+/// Kernel clock cycles in `ns`, rounded down.
+const fn cycles_down(clk: u32, ns: u32) -> u64 {
+    ns as u64 * clk as u64 / 1_000_000_000
+}
+
+/// TIMINGR examples from the reference manuals, analog filter on.
+#[cfg(test)]
+const RM_EXAMPLES: [(u32, Speed, u32); 6] = [
+    (16_000_000, Speed::Standard, 0x30420f13),
+    (16_000_000, Speed::Fast,     0x10320309),
+    (16_000_000, Speed::FastPlus, 0x00200204),
+    (48_000_000, Speed::Standard, 0xb0420f13),
+    (48_000_000, Speed::Fast,     0x50330309),
+    (48_000_000, Speed::FastPlus, 0x50100103),
+];
+
+/// The SCL rate for a TIMINGR value, with the synchronization delays as
+/// `Timing::new()` takes them.
+#[cfg(test)]
+fn scl_rate(clk: u32, timingr: u32, rise: u32, fall: u32) -> u64 {
+    let presc = (timingr >> 28) as u64 + 1;
+    let sclh = (timingr >> 8 & 0xff) as u64 + 1;
+    let scll = (timingr & 0xff) as u64 + 1;
+    let sync = cycles_down(clk, rise + fall + 2 * AF_MIN) + 4;
+    clk as u64 / ((sclh + scll) * presc + sync)
+}
+
+#[test]
+fn test_reference_manual() {
+    for (clk, speed, rm) in RM_EXAMPLES {
+        // The worst case rise and fall times.
+        let l = speed.limits();
+        let t = Timing::new(clk, speed, l.rise_max, l.fall_max);
+        assert_eq!(t.speed, speed);
+        // Not over the speed, and no slower than the reference manual.
+        let rate = scl_rate(clk, t.timingr(), l.rise_max, l.fall_max);
+        let rm_rate = scl_rate(clk, rm, l.rise_max, l.fall_max);
+        assert!(rate <= l.freq as u64, "{clk} {speed:?} at {rate}");
+        assert!(rate >= rm_rate, "{clk} {speed:?} at {rate} < {rm_rate}");
+        // The data setup time covers the rise time.
+        let div = t.presc as u64 + 1;
+        assert!((t.scldel as u64 + 1) * div
+                >= cycles_up(clk, l.rise_max + l.su_dat_min));
+    }
+}
+
+#[test]
+fn test_timingr() {
+    let t = |clk, speed, rise, fall| Timing::new(clk, speed, rise, fall)
+        .timingr();
+    assert_eq!(t(16_000_000, Speed::Standard, 1000, 300), 0x10911e24);
+    assert_eq!(t(16_000_000, Speed::Fast, 300, 300), 0x00610611);
+    assert_eq!(t(16_000_000, Speed::FastPlus, 120, 120), 0x00200105);
+    assert_eq!(t(48_000_000, Speed::Standard, 1000, 300), 0x30e32e37);
+    assert_eq!(t(48_000_000, Speed::Fast, 300, 300), 0x10950c1c);
+    assert_eq!(t(48_000_000, Speed::FastPlus, 120, 120), 0x00810813);
+    // Faster edges shorten the setup time.
+    assert_eq!(t(48_000_000, Speed::Fast, 100, 10), 0x00902345);
+}
+
+#[test]
+#[should_panic(expected = "I2C kernel clock too slow for bus speed")]
+fn test_clock_too_slow() {
+    Timing::new(4_000_000, Speed::FastPlus, 0, 0);
+}
+
+#[test]
+#[should_panic(expected = "I2C kernel clock too slow for bus speed")]
+fn test_standard_clock_too_slow() {
+    Timing::new(1_000_000, Speed::Standard, 0, 0);
+}
+
+#[test]
+#[should_panic(expected = "I2C rise time too slow")]
+fn test_rise_too_slow() {
+    Timing::new(16_000_000, Speed::Fast, 400, 100);
+}
+
+#[test]
+#[should_panic(expected = "I2C timing cannot be met")]
+fn test_clock_too_fast() {
+    // SCLDEL overflows even with the largest prescaler.
+    Timing::new(400_000_000, Speed::Standard, 1000, 300);
+}
@@ -0,0 +1,106 @@
+//! Waking from Stop mode on a target address match.
+//!
+//! With `TargetMeta::wakeup()` set, the I2C keeps listening while the MCU is
+//! in Stop mode, and an address match wakes it.  The clock is stretched until
+//! the ISR has handled the address, so the transfer then goes through the
+//! target callbacks as usual.
+//!
+//! In Stop mode the APB clock is off, so the I2C kernel clock must be the HSI,
+//! which the I2C starts by itself on seeing a START.  `initialize()` selects
+//! it, and the `Timing` must be for the HSI frequency: 16MHz on the G030 and
+//! U031, or HSI / HSIDIV (32MHz from reset) on the H503.  Only some instances
+//! can wake the MCU: I2C1 on the G030, I2C1 and I2C3 on the U031, and I2C1 and
+//! I2C2 on the H503.  Their EXTI lines are unmasked from reset.
+//!
+//! The MCU wakes with the HSI as system clock, so an application running from
+//! the PLL must restore it before relying on the clock frequency.
+
+use crate::interrupt::free;
+use crate::stm32::i2c1::RegisterBlock;
+use crate::utils::WFI;
+
+use super::target::{I2cTarget, TargetMeta};
+
+macro_rules!dbgln {($($tt:tt)*) => {if false {crate::dbgln!($($tt)*)}};}
+
+/// SCB.SCR.SLEEPDEEP, making WFI enter Stop mode.
+const SLEEPDEEP: u32 = 1 << 2;
+
+/// The shift of the kernel clock selection field in RCC.CCIPR (CCIPR4 on the
+/// H503), for an instance that can wake from Stop.  The HSI is selection 2 on
+/// all of these.
+#[cfg(feature = "cpu_stm32g030")]
+fn kernel_clock_shift(i2c: *const RegisterBlock) -> Option<u32> {
+    use crate::stm32::I2C1;
+    if i2c == I2C1::ptr() {Some(12)} else {None}
+}
+
+#[cfg(feature = "cpu_stm32u031")]
+fn kernel_clock_shift(i2c: *const RegisterBlock) -> Option<u32> {
+    use crate::stm32::{I2C1, I2C3};
+    if i2c == I2C1::ptr() {Some(12)}
+    else if i2c == I2C3::ptr() {Some(16)}
+    else {None}
+}
+
+#[cfg(feature = "cpu_stm32h503")]
+fn kernel_clock_shift(i2c: *const RegisterBlock) -> Option<u32> {
+    use crate::stm32::{I2C1, I2C2};
+    if i2c == I2C1::ptr() {Some(16)}
+    else if i2c == I2C2::ptr() {Some(18)}
+    else {None}
+}
+
+/// Run the I2C from the HSI, so that it can wake us from Stop.
+pub fn hsi_kernel_clock(i2c: &RegisterBlock) {
+    let Some(shift) = kernel_clock_shift(i2c) else {
+        panic!("I2C instance cannot wake from Stop");
+    };
+    let rcc = unsafe {&*crate::stm32::RCC::ptr()};
+    #[cfg(not(feature = "cpu_stm32h503"))]
+    rcc.CCIPR.modify(|r,w| w.bits(r.bits() & !(3 << shift) | 2 << shift));
+    #[cfg(feature = "cpu_stm32h503")]
+    rcc.CCIPR4.modify(|r,w| w.bits(r.bits() & !(3 << shift) | 2 << shift));
+}
+
+/// Select Stop mode, rather than Standby or Sleep, for a deep sleep.  Stop 1
+/// on the G030 and U031, the lowest Stop mode that all the wake-up capable
+/// instances support.
+fn select_stop() {
+    #[cfg(not(feature = "cpu_stm32h503"))]
+    {
+        // PWR registers need the APB clock, APBENR1.PWREN.
+        let rcc = unsafe {&*crate::stm32::RCC::ptr()};
+        rcc.APBENR1.modify(|r,w| w.bits(r.bits() | 1 << 28));
+        let pwr = unsafe {&*crate::stm32::PWR::ptr()};
+        pwr.CR1.modify(|r,w| w.bits(r.bits() & !7 | 1));
+    }
+    #[cfg(feature = "cpu_stm32h503")]
+    {
+        let pwr = unsafe {&*crate::stm32::PWR::ptr()};
+        pwr.PMCR.modify(|r,w| w.bits(r.bits() & !1));
+    }
+}
+
+impl<M: TargetMeta> I2cTarget<M> {
+    /// Enter Stop mode until an interrupt, e.g., an address match, wakes us.
+    /// Returns false, without stopping, if a transfer is in progress, as the
+    /// DMA does not run in Stop mode.  Any interrupt handler runs on return.
+    pub fn stop(&self) -> bool {
+        let i2c = self.meta.i2c();
+        let scb = unsafe {&*cortex_m::peripheral::SCB::PTR};
+        select_stop();
+        // With interrupts off, an address match after the check still wakes
+        // the WFI, and its handler runs once we re-enable.
+        free(|| {
+            if !self.idle() || i2c.ISR.read().BUSY().bit() {
+                return false;
+            }
+            dbgln!("I2C target Stop");
+            unsafe {scb.scr.modify(|r| r | SLEEPDEEP)};
+            WFI();
+            unsafe {scb.scr.modify(|r| r & !SLEEPDEEP)};
+            true
+        })
+    }
+}
@@ -18,6 +18,14 @@ pub fn disable_all() {
     cortex_m::interrupt::disable()
 }
 
+/// Run `f` with interrupts disabled, restoring the previous state afterwards.
+pub fn free<R>(f: impl FnOnce() -> R) -> R {
+    #[cfg(target_arch = "arm")]
+    return cortex_m::interrupt::free(|_| f());
+    #[cfg(not(target_arch = "arm"))]
+    f()
+}
+
 pub fn enable(n: crate::stm32::Interrupt) {
     let nvic = unsafe {&*cortex_m::peripheral::NVIC::PTR};
     let bit: usize = n as usize % 32;
@@ -1,4 +1,4 @@
-#![no_std]
+#![cfg_attr(not(test), no_std)]
 #![allow(incomplete_features)]
 #![deny(warnings)]
 #![feature(associated_type_defaults)]
@@ -12,6 +12,7 @@
 pub mod dma;
 #[macro_use]
 pub mod debug;
+pub mod future;
 pub mod i2c;
 pub mod interrupt;
 #[cfg(feature = "cpu_stm32h503")]
@@ -5,6 +5,7 @@ pub mod string;
 pub mod types;
 
 // use crate::cpu::{CPU_FREQ, interrupt, nothing};
+use crate::future::Signal;
 use crate::usb::hardware::{
     CTRL_RX_OFFSET, CheprWriter, bd_control, chep_block, chep_ctrl};
 use crate::usb::types::{SetupHeader, SetupResult};
@@ -85,6 +86,10 @@ pub struct USB_State<UT: USBMeta> {
     setup_rx_cb: Option<fn() -> bool>,
     /// Callback for post-setup IN data (or ACK) completion.
     setup_tx_cb: control::SetupTxCallback,
+    /// Signalled after each endpoint RX handler runs, for async tasks.
+    pub rx_signal: [Signal; 8],
+    /// Signalled after each endpoint TX handler runs, for async tasks.
+    pub tx_signal: [Signal; 8],
 
     pub ep1: UT::EP1,
     pub ep2: UT::EP2,
@@ -117,6 +122,8 @@ impl<UT: USBMeta + const Default> const Default for USB_State<UT> {
         configured: false,
         setup_rx_cb: None,
         setup_tx_cb: None,
+        rx_signal: [const {Signal::new()}; _],
+        tx_signal: [const {Signal::new()}; _],
 
         ep1: Default::default(),
         ep2: Default::default(),
@@ -215,6 +222,13 @@ impl<UT: USBMeta> USB_State<UT> {
                     break;  // FIXME, this will hang!
                 },
             }
+            let n = istr.bits() as usize & 7;
+            if istr.bits() & 16 != 0 {
+                self.rx_signal[n].signal();
+            }
+            else {
+                self.tx_signal[n].signal();
+            }
             istr = usb.ISTR.read();
         }
 
@@ -242,6 +256,10 @@ impl<UT: USBMeta> USB_State<UT> {
         let usb = unsafe {&*stm32h503::USB::ptr()};
         usb_dbgln!("USB initialize...");
 
+        // Release anyone waiting, before we reset the state.
+        for s in self.rx_signal.iter().chain(&self.tx_signal) {
+            s.signal();
+        }
         self.control_initialize();
 
         usb.CNTR.write(
@@ -16,6 +16,18 @@ pub fn WFE() {
     }
 }
 
+#[inline(always)]
+#[allow(non_snake_case)]
+pub fn WFI() {
+    if cfg!(target_arch = "arm") {
+        unsafe {
+            core::arch::asm!("wfi", options(nomem, preserves_flags, nostack))};
+    }
+    else {
+        panic!("wfi!");
+    }
+}
+
 #[inline(always)]
 pub fn nothing() {
     unsafe {core::arch::asm!("", options(nomem, nostack, preserves_flags))}
@@ -51,19 +51,13 @@ impl<T: Sync> core::ops::Deref for UCell<T> {
     fn deref(&self) -> &T {self.as_ref()}
 }
 
-macro_rules! VCellImpl {
-    ($($t:ty),*) => {$(
-        impl VCell<$t> {
-            #[inline(always)]
-            pub fn read(&self) -> $t {
-                unsafe {core::ptr::read_volatile(self.as_ptr())}
-            }
-            #[inline(always)]
-            pub fn write(&self, v: $t) {
-                unsafe {core::ptr::write_volatile(self.as_ptr(), v)};
-            }
-        }
-    )*};
+impl<T: Copy> VCell<T> {
+    #[inline(always)]
+    pub fn read(&self) -> T {
+        unsafe {core::ptr::read_volatile(self.as_ptr())}
+    }
+    #[inline(always)]
+    pub fn write(&self, v: T) {
+        unsafe {core::ptr::write_volatile(self.as_ptr(), v)};
+    }
 }
-
-VCellImpl!(bool, i8, u8, i16, u16, i32, u32, isize, usize, char);